@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use y_cpu::{analysis, debug, emulation::CPU, OpCodeTable};
+
+// Feeds arbitrary bytes both as a raw program and through the header parser,
+// then runs a bounded number of ticks. Any panic is a bug.
+fuzz_target!(|data: &[u8]| {
+    let _ = CPU::from_reader(data, Vec::new());
+
+    let mut inst_mem = [0; 127];
+    let len = data.len().min(inst_mem.len());
+    inst_mem[..len].copy_from_slice(&data[..len]);
+
+    let table = OpCodeTable::default();
+    analysis::jump_targets(&inst_mem, &table);
+    debug::disassemble(&inst_mem, &debug::DisasmOptions::default(), &table);
+
+    let mut cpu = CPU::new(inst_mem, Vec::new());
+    cpu.run(1_000);
+});
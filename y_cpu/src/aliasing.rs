@@ -0,0 +1,94 @@
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AliasError {
+    // The target window would run past the end of the address space
+    OutOfRange,
+    // The source window overlaps one that is already aliased
+    Overlap,
+    // The target window overlaps an aliased source, which could form a loop
+    Chain,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct AliasTable {
+    entries: Vec<(RangeInclusive<u8>, u8)>,
+}
+
+impl AliasTable {
+    pub fn add(&mut self, from: RangeInclusive<u8>, to: u8) -> Result<(), AliasError> {
+        let len = from
+            .end()
+            .checked_sub(*from.start())
+            .ok_or(AliasError::OutOfRange)?;
+        let target = to..=to.checked_add(len).ok_or(AliasError::OutOfRange)?;
+
+        let overlaps = |a: &RangeInclusive<u8>, b: &RangeInclusive<u8>| {
+            a.start() <= b.end() && b.start() <= a.end()
+        };
+        if self
+            .entries
+            .iter()
+            .any(|(source, _)| overlaps(source, &from))
+        {
+            return Err(AliasError::Overlap);
+        }
+        let chained = self.entries.iter().any(|(source, base)| {
+            let existing_target = *base..=base + (source.end() - source.start());
+            overlaps(source, &target) || overlaps(&existing_target, &from)
+        });
+        if chained || overlaps(&from, &target) {
+            return Err(AliasError::Chain);
+        }
+
+        self.entries.push((from, to));
+        Ok(())
+    }
+
+    pub fn translate(&self, addr: u8) -> u8 {
+        self.entries
+            .iter()
+            .find(|(source, _)| source.contains(&addr))
+            .map_or(addr, |(source, base)| base + (addr - source.start()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulation::CPU;
+
+    #[test]
+    fn translates_addresses_inside_the_window() {
+        let mut table = AliasTable::default();
+        table.add(200..=203, 128).unwrap();
+        assert_eq!(table.translate(199), 199);
+        assert_eq!(table.translate(200), 128);
+        assert_eq!(table.translate(203), 131);
+        assert_eq!(table.translate(204), 204);
+    }
+
+    #[test]
+    fn rejects_bad_windows() {
+        let mut table = AliasTable::default();
+        assert_eq!(table.add(10..=20, 250), Err(AliasError::OutOfRange));
+        table.add(200..=203, 128).unwrap();
+        assert_eq!(table.add(202..=205, 140), Err(AliasError::Overlap));
+        assert_eq!(table.add(128..=129, 140), Err(AliasError::Chain));
+        assert_eq!(table.add(210..=211, 201), Err(AliasError::Chain));
+        assert_eq!(table.add(220..=223, 221), Err(AliasError::Chain));
+    }
+
+    #[test]
+    fn cpu_accesses_go_through_aliases() {
+        // NOOP #07 #00, COPY #01 #C8, COPY #80 #81
+        let mut inst_mem = [0; 127];
+        inst_mem[..9].copy_from_slice(&[0x00, 0x07, 0x00, 0x0C, 0x01, 0xC8, 0x0C, 0x80, 0x81]);
+        let mut cpu = CPU::new(inst_mem, Vec::new());
+        cpu.add_alias(200..=200, 128).unwrap();
+        for _ in 0..3 {
+            cpu.tick();
+        }
+        assert_eq!(&cpu.data_mem[0..2], &[7, 7]);
+    }
+}
@@ -0,0 +1,108 @@
+// Arithmetic behind ADD, SUB, MUL and DIV. Operands arrive widened to i16
+// after the signed argument options are applied, results are stored as bytes.
+pub trait Alu {
+    fn add(&self, a: i16, b: i16) -> u8;
+    fn sub(&self, a: i16, b: i16) -> u8;
+    fn mul(&self, a: i16, b: i16) -> u8;
+    // None raises the division fault
+    fn div(&self, a: i16, b: i16) -> Option<u8>;
+}
+
+// How a signed quotient with a remainder is rounded, -7 / 2 is -3 when
+// truncating and -4 when flooring
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DivRounding {
+    #[default]
+    Truncate,
+    Floor,
+}
+
+// Two's complement wrapping arithmetic, division by zero faults
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StandardAlu {
+    pub rounding: DivRounding,
+}
+
+impl Alu for StandardAlu {
+    fn add(&self, a: i16, b: i16) -> u8 {
+        (a + b) as u8
+    }
+
+    fn sub(&self, a: i16, b: i16) -> u8 {
+        (a - b) as u8
+    }
+
+    fn mul(&self, a: i16, b: i16) -> u8 {
+        a.wrapping_mul(b) as u8
+    }
+
+    fn div(&self, a: i16, b: i16) -> Option<u8> {
+        let quotient = a.checked_div(b)?;
+        let inexact = a % b != 0 && (a < 0) != (b < 0);
+        let quotient = match self.rounding {
+            DivRounding::Floor if inexact => quotient - 1,
+            _ => quotient,
+        };
+        Some(quotient as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, emulation::CPU};
+
+    // Saturates instead of wrapping
+    struct SaturatingAlu;
+
+    impl Alu for SaturatingAlu {
+        fn add(&self, a: i16, b: i16) -> u8 {
+            (a + b).clamp(0, 255) as u8
+        }
+
+        fn sub(&self, a: i16, b: i16) -> u8 {
+            (a - b).clamp(0, 255) as u8
+        }
+
+        fn mul(&self, a: i16, b: i16) -> u8 {
+            (a * b).clamp(0, 255) as u8
+        }
+
+        fn div(&self, a: i16, b: i16) -> Option<u8> {
+            StandardAlu::default().div(a, b)
+        }
+    }
+
+    #[test]
+    fn standard_alu_wraps() {
+        let alu = StandardAlu::default();
+        assert_eq!(alu.add(200, 100), 44);
+        assert_eq!(alu.sub(1, 2), 255);
+        assert_eq!(alu.mul(16, 17), 16);
+        assert_eq!(alu.div(7, 0), None);
+    }
+
+    #[test]
+    fn cpu_uses_the_configured_alu() {
+        let code = assembler::assemble("NOOP #C8 #64\nADD #01 #02").unwrap();
+        let mut cpu = CPU::with_image(&code, &[], Vec::new()).unwrap();
+        cpu.alu = Box::new(SaturatingAlu);
+        cpu.run(2);
+        assert_eq!(cpu.inst_mem[1], 255);
+    }
+
+    #[test]
+    fn division_rounding() {
+        let truncate = StandardAlu::default();
+        let floor = StandardAlu {
+            rounding: DivRounding::Floor,
+        };
+        assert_eq!(truncate.div(-7, 2), Some(-3_i8 as u8));
+        assert_eq!(floor.div(-7, 2), Some(-4_i8 as u8));
+        assert_eq!(floor.div(7, -2), Some(-4_i8 as u8));
+        // exact and same-sign quotients round the same either way
+        assert_eq!(floor.div(-8, 2), Some(-4_i8 as u8));
+        assert_eq!(floor.div(-7, -2), Some(3));
+        assert_eq!(floor.div(7, 2), Some(3));
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+};
+
+use crate::{Instruction, OpCode, OpCodeTable};
+
+// How control leaves an instruction. Jumps are stores to the PC at address 0,
+// which is advanced by 3 afterwards like after any other instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Next,
+    // A comparison either falls through or skips the next instruction
+    Skip,
+    Jump(u8),
+    // The new PC depends on state only known at run time
+    Computed,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct JumpTargets {
+    pub targets: BTreeSet<u8>,
+    // Addresses of instructions whose target could not be determined
+    pub computed: BTreeSet<u8>,
+}
+
+pub fn instructions<'a>(
+    program: &'a [u8],
+    table: &'a OpCodeTable,
+) -> impl Iterator<Item = (u8, Instruction)> + 'a {
+    program
+        .chunks_exact(3)
+        .zip((0..=u8::MAX).step_by(3))
+        .map(|(raw, addr)| {
+            (
+                addr,
+                Instruction::decode_with([raw[0], raw[1], raw[2]], table),
+            )
+        })
+}
+
+// Best effort: instruction memory is assumed not to be modified at run time
+pub fn flow(program: &[u8], inst: &Instruction) -> Flow {
+    use OpCode::*;
+    let (arg1, arg2) = (inst.arg1.address(), inst.arg2.address());
+    match inst.code {
+        NoOp => Flow::Next,
+        CompEq | CompGt | CompLt => Flow::Skip,
+        Copy if arg2 != 0 => Flow::Next,
+        Copy => match arg1 {
+            0 => Flow::Next,
+            1..=127 => {
+                let target = program.get(arg1 as usize).copied().unwrap_or(0);
+                Flow::Jump(target.wrapping_add(3))
+            }
+            _ => Flow::Computed,
+        },
+        _ if arg1 == 0 => Flow::Computed,
+        _ => Flow::Next,
+    }
+}
+
+pub fn jump_targets(program: &[u8], table: &OpCodeTable) -> JumpTargets {
+    let mut result = JumpTargets::default();
+    for (addr, inst) in instructions(program, table) {
+        match flow(program, &inst) {
+            Flow::Next => {}
+            Flow::Skip => {
+                result.targets.insert(addr.wrapping_add(6));
+            }
+            Flow::Jump(target) => {
+                result.targets.insert(target);
+            }
+            Flow::Computed => {
+                result.computed.insert(addr);
+            }
+        }
+    }
+    result
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Reachable {
+    pub addresses: BTreeSet<u8>,
+    // False if a computed jump was reached, so more code may be live
+    pub complete: bool,
+}
+
+// Follows fall-through, skips and jumps from `entry` through the instructions
+// contained in `program`
+pub fn reachable_addresses(program: &[u8], entry: u8, table: &OpCodeTable) -> Reachable {
+    let mut reachable = Reachable {
+        addresses: BTreeSet::new(),
+        complete: true,
+    };
+    let mut pending = vec![entry];
+    while let Some(addr) = pending.pop() {
+        let Some(raw) = program.get(addr as usize..addr as usize + 3) else {
+            continue;
+        };
+        if !reachable.addresses.insert(addr) {
+            continue;
+        }
+        let inst = Instruction::decode_with([raw[0], raw[1], raw[2]], table);
+        match flow(program, &inst) {
+            Flow::Next => pending.push(addr.wrapping_add(3)),
+            Flow::Skip => pending.extend([addr.wrapping_add(3), addr.wrapping_add(6)]),
+            Flow::Jump(target) => pending.push(target),
+            Flow::Computed => reachable.complete = false,
+        }
+    }
+    reachable
+}
+
+// Straight runs of instructions, `end` is the address of the last one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub start: u8,
+    pub end: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Edge {
+    To(u8),
+    // The block ends in a jump whose target is only known at run time
+    Computed,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    // Keyed by the start of the block the edge leaves
+    pub edges: BTreeSet<(u8, Edge)>,
+}
+
+impl Cfg {
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n");
+        for block in &self.blocks {
+            writeln!(
+                dot,
+                "    b{:02X} [label=\"{:02X}-{:02X}\"];",
+                block.start, block.start, block.end
+            )
+            .unwrap();
+        }
+        for (from, edge) in &self.edges {
+            match edge {
+                Edge::To(to) => writeln!(dot, "    b{from:02X} -> b{to:02X};").unwrap(),
+                Edge::Computed => {
+                    writeln!(dot, "    b{from:02X} -> computed [style=dashed];").unwrap()
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+// Blocks start at 0, at every jump or skip target and after every
+// instruction that can change the flow
+pub fn control_flow_graph(program: &[u8], table: &OpCodeTable) -> Cfg {
+    let instructions: Vec<_> = instructions(program, table)
+        .map(|(addr, inst)| (addr, flow(program, &inst)))
+        .collect();
+
+    let mut leaders = BTreeSet::from([0]);
+    for &(addr, flow) in &instructions {
+        match flow {
+            Flow::Next => {}
+            Flow::Skip => leaders.extend([addr.wrapping_add(3), addr.wrapping_add(6)]),
+            Flow::Jump(target) => leaders.extend([addr.wrapping_add(3), target]),
+            Flow::Computed => {
+                leaders.insert(addr.wrapping_add(3));
+            }
+        }
+    }
+
+    let mut cfg = Cfg::default();
+    for (i, &(addr, flow)) in instructions.iter().enumerate() {
+        if leaders.contains(&addr) {
+            cfg.blocks.push(Block {
+                start: addr,
+                end: addr,
+            });
+        }
+        let Some(block) = cfg.blocks.last_mut() else {
+            continue;
+        };
+        block.end = addr;
+        let start = block.start;
+        let next = addr.wrapping_add(3);
+        let is_last = i + 1 == instructions.len() || leaders.contains(&next);
+        let successors = match flow {
+            Flow::Next if is_last => vec![Edge::To(next)],
+            Flow::Next => vec![],
+            Flow::Skip => vec![Edge::To(next), Edge::To(addr.wrapping_add(6))],
+            Flow::Jump(target) => vec![Edge::To(target)],
+            Flow::Computed => vec![Edge::Computed],
+        };
+        cfg.edges
+            .extend(successors.into_iter().map(|edge| (start, edge)));
+    }
+    // Edges running off the end of the program or into the middle of an
+    // instruction are dropped
+    let starts: BTreeSet<u8> = cfg.blocks.iter().map(|block| block.start).collect();
+    cfg.edges.retain(|(_, edge)| match edge {
+        Edge::To(to) => starts.contains(to),
+        Edge::Computed => true,
+    });
+    cfg
+}
+
+// Longest number of ticks a run from address 0 can take before it falls off
+// the end of instruction memory. The program is zero padded to the full bank
+// like a freshly loaded CPU. Device latency is not included, and loops or
+// computed jumps make the bound unknown.
+pub fn static_cycle_bound(program: &[u8], table: &OpCodeTable) -> Option<u64> {
+    let mut memory = [0; 127];
+    let len = program.len().min(memory.len());
+    memory[..len].copy_from_slice(&program[..len]);
+    cycle_bound(&memory, table, 0, &mut HashMap::new())
+}
+
+// `bounds` holds None for instructions still being visited
+fn cycle_bound(
+    memory: &[u8; 127],
+    table: &OpCodeTable,
+    addr: u8,
+    bounds: &mut HashMap<u8, Option<u64>>,
+) -> Option<u64> {
+    if addr as usize + 2 >= memory.len() {
+        return Some(0);
+    }
+    if let Some(&bound) = bounds.get(&addr) {
+        return bound;
+    }
+    bounds.insert(addr, None);
+
+    let raw = &memory[addr as usize..addr as usize + 3];
+    let inst = Instruction::decode_with([raw[0], raw[1], raw[2]], table);
+    let rest = match flow(memory, &inst) {
+        Flow::Next => cycle_bound(memory, table, addr + 3, bounds)?,
+        Flow::Skip => {
+            let next = cycle_bound(memory, table, addr + 3, bounds)?;
+            next.max(cycle_bound(memory, table, addr + 6, bounds)?)
+        }
+        Flow::Jump(target) => cycle_bound(memory, table, target, bounds)?,
+        Flow::Computed => return None,
+    };
+    bounds.insert(addr, Some(rest + 1));
+    Some(rest + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler;
+
+    fn table() -> OpCodeTable {
+        OpCodeTable::default()
+    }
+
+    fn sum_loop() -> Vec<u8> {
+        assembler::assemble(include_str!("../fixtures/sum_loop.ysm")).unwrap()
+    }
+
+    #[test]
+    fn finds_jump_and_skip_targets() {
+        let targets = jump_targets(&sum_loop(), &table());
+        assert_eq!(targets.targets, BTreeSet::from([9, 21]));
+        assert!(targets.computed.is_empty());
+    }
+
+    #[test]
+    fn stores_to_the_pc_from_data_are_computed() {
+        let program = assembler::assemble("NOOP #00 #00\nCOPY #80 #00\nADD #00 #80").unwrap();
+        let targets = jump_targets(&program, &table());
+        assert!(targets.targets.is_empty());
+        assert_eq!(targets.computed, BTreeSet::from([3, 6]));
+    }
+
+    #[test]
+    fn static_cycle_bound_follows_the_longest_path() {
+        // 42 instructions fit the bank, zero padding runs as NOOPs
+        assert_eq!(static_cycle_bound(&[], &table()), Some(42));
+        let program =
+            assembler::assemble("NOOP $end #00\nCOPY #01 #00\nNOOP #00 #00\n$end").unwrap();
+        assert_eq!(static_cycle_bound(&program, &table()), Some(41));
+        let program = assembler::assemble("COMPEQ #00 #00\nNOOP #00 #00").unwrap();
+        assert_eq!(static_cycle_bound(&program, &table()), Some(42));
+    }
+
+    #[test]
+    fn loops_and_computed_jumps_have_no_bound() {
+        assert_eq!(static_cycle_bound(&sum_loop(), &table()), None);
+        let program = assembler::assemble("COPY #80 #00").unwrap();
+        assert_eq!(static_cycle_bound(&program, &table()), None);
+    }
+
+    #[test]
+    fn control_flow_graph_of_a_loop() {
+        let cfg = control_flow_graph(&sum_loop(), &table());
+        assert_eq!(
+            cfg.blocks,
+            [
+                Block { start: 0, end: 6 },
+                Block { start: 9, end: 15 },
+                Block { start: 18, end: 18 }
+            ]
+        );
+        // the skip past the last instruction leaves the program and is dropped
+        assert_eq!(
+            cfg.edges,
+            BTreeSet::from([(0, Edge::To(9)), (9, Edge::To(18)), (18, Edge::To(9))])
+        );
+        assert_eq!(
+            cfg.to_dot(),
+            "digraph cfg {\n    b00 [label=\"00-06\"];\n    b09 [label=\"09-0F\"];\n    b12 [label=\"12-12\"];\n    b00 -> b09;\n    b09 -> b12;\n    b12 -> b09;\n}\n"
+        );
+    }
+
+    #[test]
+    fn computed_jumps_end_a_block() {
+        let program = assembler::assemble("COPY #80 #00\nNOOP #00 #00").unwrap();
+        let cfg = control_flow_graph(&program, &table());
+        assert_eq!(cfg.blocks.len(), 2);
+        assert!(cfg.edges.contains(&(0, Edge::Computed)));
+        assert!(cfg.to_dot().contains("b00 -> computed [style=dashed];"));
+    }
+
+    #[test]
+    fn reachable_follows_jumps_and_skips() {
+        let reachable = reachable_addresses(&sum_loop(), 0, &table());
+        assert_eq!(
+            reachable.addresses,
+            BTreeSet::from([0, 3, 6, 9, 12, 15, 18])
+        );
+        assert!(reachable.complete);
+
+        // the jump skips the instruction at 6
+        let program =
+            assembler::assemble("NOOP $end #00\nCOPY #01 #00\nNOOP #00 #00\n$end\nNOOP #00 #00")
+                .unwrap();
+        let reachable = reachable_addresses(&program, 0, &table());
+        assert_eq!(reachable.addresses, BTreeSet::from([0, 3, 9]));
+    }
+
+    #[test]
+    fn computed_jumps_make_reachability_incomplete() {
+        let program = assembler::assemble("COPY #80 #00\nNOOP #00 #00").unwrap();
+        let reachable = reachable_addresses(&program, 0, &table());
+        assert_eq!(reachable.addresses, BTreeSet::from([0]));
+        assert!(!reachable.complete);
+        assert!(reachable_addresses(&program, 200, &table())
+            .addresses
+            .is_empty());
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    error::Error,
+    fmt::{self, Write},
+};
+
+use crate::{
+    debug::{DebugInfo, Import},
+    emulation::CPU,
+    symbols::{Symbol, SymbolTable},
+    Instruction,
+};
+
+pub const DEFAULT_FUEL: u64 = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssembleError {
+    UnknownInstruction { line: usize },
+    DuplicateLabel { name: String, line: usize },
+    UndefinedLabel { name: String, line: usize },
+    TooLong { line: usize },
+    BadHex { token: String, line: usize },
+    BadOffset { token: String, line: usize },
+    TooManyOptionBits { line: usize },
+    BadPrefix { token: String, line: usize },
+    ArgumentCount { line: usize },
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssembleError::UnknownInstruction { line } => {
+                write!(f, "Unknown instruction on line {line}")
+            }
+            AssembleError::DuplicateLabel { name, line } => {
+                write!(f, "Duplicate label {name} on line {line}")
+            }
+            AssembleError::UndefinedLabel { name, line } => {
+                write!(f, "Undefined label {name} on line {line}")
+            }
+            AssembleError::TooLong { line } => {
+                write!(f, "Program exceeds instruction memory on line {line}")
+            }
+            AssembleError::BadHex { token, line } => {
+                write!(f, "Bad hex address {token} on line {line}")
+            }
+            AssembleError::BadOffset { token, line } => {
+                write!(f, "Bad label offset {token} on line {line}")
+            }
+            AssembleError::TooManyOptionBits { line } => {
+                write!(f, "More than 4 option bits on line {line}")
+            }
+            AssembleError::BadPrefix { token, line } => {
+                write!(f, "Unknown operand {token} on line {line}")
+            }
+            AssembleError::ArgumentCount { line } => {
+                write!(f, "Expected 2 operands on line {line}")
+            }
+        }
+    }
+}
+
+impl Error for AssembleError {}
+
+impl AssembleError {
+    pub fn line(&self) -> usize {
+        match self {
+            AssembleError::UnknownInstruction { line }
+            | AssembleError::DuplicateLabel { line, .. }
+            | AssembleError::UndefinedLabel { line, .. }
+            | AssembleError::TooLong { line }
+            | AssembleError::BadHex { line, .. }
+            | AssembleError::BadOffset { line, .. }
+            | AssembleError::TooManyOptionBits { line }
+            | AssembleError::BadPrefix { line, .. }
+            | AssembleError::ArgumentCount { line } => *line,
+        }
+    }
+
+    // Formats the error with the offending line of `source` and a marker under
+    // the part of it that caused the error
+    pub fn render(&self, source: &str) -> String {
+        let line = self.line();
+        let text = source.lines().nth(line - 1).unwrap_or_default();
+        let token = match self {
+            AssembleError::DuplicateLabel { name, .. } => format!("${name}"),
+            AssembleError::UndefinedLabel { name, .. } => name
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_owned(),
+            AssembleError::BadHex { token, .. }
+            | AssembleError::BadOffset { token, .. }
+            | AssembleError::BadPrefix { token, .. } => token.clone(),
+            AssembleError::TooManyOptionBits { .. } => text
+                .split_whitespace()
+                .find(|part| part.starts_with(':'))
+                .unwrap_or_default()
+                .to_owned(),
+            _ => text
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_owned(),
+        };
+        let column = text.find(&token).unwrap_or(0);
+        let gutter = " ".repeat(line.to_string().len());
+
+        let mut output = format!("error: {self}\n");
+        writeln!(output, "{gutter} |").unwrap();
+        writeln!(output, "{line} | {text}").unwrap();
+        writeln!(
+            output,
+            "{gutter} | {}{}",
+            " ".repeat(column),
+            "^".repeat(token.len().max(1))
+        )
+        .unwrap();
+        output
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    assemble_with_debug(source).map(|(program, _)| program)
+}
+
+// Also maps every emitted instruction back to the source line it came from
+pub fn assemble_with_debug(source: &str) -> Result<(Vec<u8>, DebugInfo), AssembleError> {
+    assemble_unit(source, false)
+}
+
+// Assembles one module for linker::link. Labels the module does not define
+// are left as imports, encoded as 0 until the linker fills them in.
+pub fn assemble_module(source: &str) -> Result<(Vec<u8>, DebugInfo), AssembleError> {
+    assemble_unit(source, true)
+}
+
+fn assemble_unit(source: &str, allow_imports: bool) -> Result<(Vec<u8>, DebugInfo), AssembleError> {
+    let mut instructions = Vec::new();
+    let mut symbols = SymbolTable::new();
+    let mut pos = 0_u8;
+
+    for (text, line) in source.lines().zip(1..) {
+        if text.starts_with("//") || text.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = text.strip_prefix('$') {
+            // Jumps add 3 after storing the label, so a label on the first
+            // instruction wraps around to 253
+            if symbols.insert(name, pos.wrapping_sub(3)).is_some() {
+                let name = name.to_owned();
+                return Err(AssembleError::DuplicateLabel { name, line });
+            }
+        } else {
+            let instr = Instruction::from_text(text, line)?;
+            if pos as usize + 3 > 127 {
+                return Err(AssembleError::TooLong { line });
+            }
+            instructions.push((instr, line));
+            pos += 3;
+        }
+    }
+
+    let mut relocations = Vec::new();
+    let mut imports = Vec::new();
+    for (
+        (
+            Instruction {
+                code, arg1, arg2, ..
+            },
+            line,
+        ),
+        start,
+    ) in instructions.iter_mut().zip((0..).step_by(3))
+    {
+        let used = code.operands();
+        for (arg, offset) in [(arg1, 1), (arg2, 2)] {
+            let Symbol::UnResolved(name, addend) = arg.clone() else {
+                // Absolute operands into instruction memory refer to the
+                // program itself and move with it too
+                if offset <= used && (1..=126).contains(&arg.address()) {
+                    relocations.push(start + offset);
+                }
+                continue;
+            };
+            if arg.resolve(&symbols) {
+                relocations.push(start + offset);
+            } else if allow_imports {
+                imports.push(Import {
+                    offset: start + offset,
+                    name,
+                    addend,
+                });
+                *arg = Symbol::Resolved(0);
+            } else {
+                let name = arg.to_string();
+                return Err(AssembleError::UndefinedLabel { name, line: *line });
+            }
+        }
+    }
+    let labels = symbols
+        .iter()
+        .map(|(name, addr)| (name.to_string(), *addr))
+        .collect();
+
+    let program = instructions
+        .iter()
+        .flat_map(|(instr, _)| instr.encode())
+        .collect();
+    let lines = (0..)
+        .step_by(3)
+        .zip(instructions.iter().map(|(_, line)| *line));
+    Ok((
+        program,
+        DebugInfo {
+            lines: lines.collect(),
+            relocations,
+            labels,
+            imports,
+        },
+    ))
+}
+
+// Assembles `source` and runs it on a fresh CPU without devices until it
+// halts or `DEFAULT_FUEL` ticks have passed
+pub fn run_source(source: &str) -> Result<CPU, AssembleError> {
+    let (program, debug_info) = assemble_with_debug(source)?;
+    let mut inst_mem = [0; 127];
+    inst_mem[..program.len()].copy_from_slice(&program);
+    let mut cpu = CPU::new(inst_mem, Vec::new());
+    cpu.program_len = Some(program.len());
+    cpu.debug_info = Some(debug_info);
+    cpu.run(DEFAULT_FUEL);
+    Ok(cpu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_labels_to_the_address_before_them() {
+        let program = assemble("NOOP #00 #00\nNOOP $end #00\n$end\nNOOP #00 #00").unwrap();
+        assert_eq!(program[4], 3);
+    }
+
+    #[test]
+    fn rejects_duplicate_labels() {
+        let error = assemble("NOOP #00 #00\n$a\nNOOP #00 #00\n$a").unwrap_err();
+        assert_eq!(
+            error,
+            AssembleError::DuplicateLabel {
+                name: "a".to_owned(),
+                line: 4
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_undefined_labels() {
+        let error = assemble("NOOP #00 #00\nCOPY $missing #00").unwrap_err();
+        assert_eq!(
+            error,
+            AssembleError::UndefinedLabel {
+                name: "$missing".to_owned(),
+                line: 2
+            }
+        );
+    }
+
+    #[test]
+    fn label_before_the_first_instruction() {
+        let program = assemble("$start\nNOOP $start #00").unwrap();
+        assert_eq!(program, [0, 253, 0]);
+
+        // Counts #80 up to 3, jumping back to the label at the start
+        let cpu = run_source(
+            "$start\nNOOP $start #01\nADD #80 #02\nCOMPGT #0E #80\nCOPY #01 #00\nNOOP #00 #03",
+        );
+        assert_eq!(cpu.unwrap().data_mem[0], 3);
+    }
+
+    #[test]
+    fn run_source_runs_to_completion() {
+        let cpu = run_source("NOOP #06 #07\nCOPY #01 #80\nMUL #80 #02").unwrap();
+        assert_eq!(cpu.data_mem[0], 42);
+        assert_eq!(cpu.program_len, Some(9));
+        assert_eq!(cpu.source_line(3), Some(2));
+        assert!(run_source("BOGUS #00 #00").is_err());
+    }
+
+    #[test]
+    fn debug_info_maps_addresses_to_source_lines() {
+        let source = "// start\nNOOP #00 #00\n\n$loop\nCOPY #01 #80";
+        let (_, info) = assemble_with_debug(source).unwrap();
+        assert_eq!(info.lines.into_iter().collect::<Vec<_>>(), [(0, 2), (3, 5)]);
+
+        let cpu = run_source(source).unwrap();
+        assert_eq!(cpu.source_line(0), Some(2));
+        assert_eq!(cpu.source_line(5), Some(5));
+        assert_eq!(cpu.source_line(6), None);
+    }
+
+    #[test]
+    fn absolute_instruction_operands_are_relocated() {
+        let source = "NOOP #01 #02\nCOPY #01 #80\nNOT #05 #06\nCOPY #7F #00";
+        let (_, info) = assemble_with_debug(source).unwrap();
+        // NOOP operands are data, NOT ignores arg2, #80 is data memory and
+        // #00 is the PC
+        assert_eq!(info.relocations, [4, 7]);
+    }
+
+    #[test]
+    fn malformed_operands_are_errors() {
+        let error = |source: &str| assemble(source).unwrap_err();
+        assert_eq!(
+            error("NOOP #zz #00"),
+            AssembleError::BadHex {
+                token: "#zz".to_owned(),
+                line: 1
+            }
+        );
+        assert_eq!(
+            error("NOOP #00 #00\nNOOP :11111 #00 #00"),
+            AssembleError::TooManyOptionBits { line: 2 }
+        );
+        assert_eq!(
+            error("NOOP é #00"),
+            AssembleError::BadPrefix {
+                token: "é".to_owned(),
+                line: 1
+            }
+        );
+        assert_eq!(
+            error("NOOP #01 +1 #00"),
+            AssembleError::BadOffset {
+                token: "+1".to_owned(),
+                line: 1
+            }
+        );
+        assert_eq!(
+            error("$a\nNOOP $a +7F +7F #00"),
+            AssembleError::BadOffset {
+                token: "+7F".to_owned(),
+                line: 2
+            }
+        );
+        assert_eq!(
+            error("NOOP $a +zz #00"),
+            AssembleError::BadOffset {
+                token: "+zz".to_owned(),
+                line: 1
+            }
+        );
+        assert_eq!(error("COPY #01"), AssembleError::ArgumentCount { line: 1 });
+        assert_eq!(
+            error("COPY #01 #02 #03"),
+            AssembleError::ArgumentCount { line: 1 }
+        );
+        assert_eq!(
+            error("JUMP #01 #02"),
+            AssembleError::UnknownInstruction { line: 1 }
+        );
+    }
+
+    #[test]
+    fn render_marks_the_offending_token() {
+        let source = "NOOP #00 #00\nCOPY #01 #8G";
+        let rendered = assemble(source).unwrap_err().render(source);
+        assert_eq!(
+            rendered,
+            "error: Bad hex address #8G on line 2\n  |\n2 | COPY #01 #8G\n  |          ^^^\n"
+        );
+    }
+}
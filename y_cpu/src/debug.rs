@@ -0,0 +1,588 @@
+use std::{collections::BTreeMap, fmt::Write, mem};
+
+use crate::{
+    devices::DeviceError,
+    emulation::{Halted, LengthMismatch, CPU},
+    journal::Undo,
+    Instruction, OpCodeTable,
+};
+
+// Source line of every assembled instruction, keyed by its start address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub lines: BTreeMap<u8, usize>,
+    // Offsets of operand bytes that hold instruction memory addresses, from
+    // labels or absolute operands
+    pub relocations: Vec<u8>,
+    // Label values as stored in operands, 3 before the labelled instruction
+    pub labels: BTreeMap<String, u8>,
+    pub imports: Vec<Import>,
+}
+
+// An operand byte referring to a label defined in another module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    pub offset: u8,
+    pub name: String,
+    pub addend: i8,
+}
+
+impl DebugInfo {
+    // Also finds the line for addresses inside an instruction
+    pub fn line(&self, addr: u8) -> Option<usize> {
+        let (&start, &line) = self.lines.range(..=addr).next_back()?;
+        (addr - start < 3).then_some(line)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub pc: u8,
+    pub inst_page: u8,
+    pub data_page: u8,
+}
+
+// `old` is None for device writes, whose previous value cannot be read back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemWrite {
+    pub addr: u8,
+    pub old: Option<u8>,
+    pub new: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct StepRecord {
+    pub pc: u8,
+    // None if the PC was past the last complete instruction, the tick then
+    // only applied the end of program policy
+    pub instruction: Option<Instruction>,
+    pub before: Registers,
+    pub after: Registers,
+    pub writes: Vec<MemWrite>,
+    pub halted: Halted,
+}
+
+impl CPU {
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.reg_zero,
+            inst_page: self.inst_mem.pointer,
+            data_page: self.data_mem.pointer,
+        }
+    }
+
+    // Ticks once and reports everything the tick changed. The access log and
+    // journal are borrowed for the step and keep their own entries.
+    pub fn step_detailed(&mut self) -> StepRecord {
+        let before = self.registers();
+        let instruction = self.fetch();
+        let saved_accesses = self.accesses.replace(Vec::new());
+        let saved_journal = self.journal.replace(Vec::new());
+
+        let halted = self.tick();
+
+        let accesses = mem::replace(&mut self.accesses, saved_accesses).unwrap_or_default();
+        let journal = mem::replace(&mut self.journal, saved_journal).unwrap_or_default();
+        let mut undo = journal.iter().flat_map(|step| &step.undo);
+        let writes = accesses
+            .iter()
+            .filter(|access| access.is_write)
+            .map(|access| {
+                let old = match access.addr {
+                    0 => Some(before.pc),
+                    1..=126 | 128..=193 => undo.next().map(|undo| match *undo {
+                        Undo::Inst { value, .. } | Undo::Data { value, .. } => value,
+                        Undo::InstPage(value) | Undo::DataPage(value) => value,
+                    }),
+                    _ => None,
+                };
+                MemWrite {
+                    addr: access.addr,
+                    old,
+                    new: access.value,
+                }
+            })
+            .collect();
+
+        if let Some(log) = &mut self.accesses {
+            log.extend(accesses);
+        }
+        if let Some(log) = &mut self.journal {
+            log.extend(journal);
+        }
+        StepRecord {
+            pc: before.pc,
+            instruction,
+            before,
+            after: self.registers(),
+            writes,
+            halted,
+        }
+    }
+
+    // Loads every address of the device at `index` directly, without latency.
+    // Write-only devices fail here, and devices whose loads have side effects
+    // such as queues are consumed.
+    pub fn dump_device(&mut self, index: usize) -> Option<Result<Vec<u8>, DeviceError>> {
+        let device = self.devices.get_mut(index)?;
+        let start = device.address();
+        let dump = (0..device.size())
+            .map(|offset| device.load(start + offset))
+            .collect();
+        Some(dump)
+    }
+
+    pub fn source_line(&self, pc: u8) -> Option<usize> {
+        self.debug_info.as_ref()?.line(pc)
+    }
+
+    pub fn state_report(&self) -> String {
+        let mut report = String::new();
+
+        writeln!(report, "== Registers ==").unwrap();
+        writeln!(report, "PC:          {:#04X}", self.reg_zero).unwrap();
+        writeln!(report, "Inst page:   {:#04X}", self.inst_mem.pointer).unwrap();
+        writeln!(report, "Data page:   {:#04X}", self.data_mem.pointer).unwrap();
+        writeln!(report, "Cycles:      {}", self.cycles).unwrap();
+
+        writeln!(report, "== Data memory ==").unwrap();
+        for (row, chunk) in self.data_mem[0..64].chunks(8).enumerate() {
+            write!(report, "{:02X}:", 128 + row * 8).unwrap();
+            for byte in chunk {
+                write!(report, " {byte:02X}").unwrap();
+            }
+            writeln!(report).unwrap();
+        }
+
+        writeln!(report, "== Disassembly ==").unwrap();
+        let pc = self.reg_zero;
+        let window = [-6, -3, 0, 3, 6]
+            .into_iter()
+            .filter_map(|offset| pc.checked_add_signed(offset));
+        for addr in window {
+            if let Some(inst) = self.peek(addr) {
+                let marker = if addr == pc { '>' } else { ' ' };
+                writeln!(report, "{marker} {addr:02X}: {inst}").unwrap();
+            }
+        }
+
+        report
+    }
+
+    pub fn memory_map(&self) -> String {
+        let mut regions = vec![
+            (0, 0, "Program Counter".to_owned()),
+            (1, 126, "Instruction Memory".to_owned()),
+            (128, 191, "Data Memory".to_owned()),
+            (192, 192, "Goto Page (Instructions)".to_owned()),
+            (193, 193, "Goto Page (Data)".to_owned()),
+        ];
+        // Devices answering to no address take up no room in the map
+        for device in self.devices.iter().filter(|device| device.size() > 0) {
+            let start = device.address() as usize;
+            let end = (start + device.size() as usize - 1).min(255);
+            regions.push((start, end, device.name().to_owned()));
+        }
+        regions.sort_by_key(|(start, _, _)| *start);
+
+        let mut map = String::new();
+        let mut next = 0;
+        for (start, end, name) in regions {
+            if start > next {
+                writeln!(map, "{next:3}-{:3} unmapped", start - 1).unwrap();
+            }
+            writeln!(map, "{start:3}-{end:3} {name}").unwrap();
+            next = next.max(end + 1);
+        }
+        if next <= 255 {
+            writeln!(map, "{next:3}-255 unmapped").unwrap();
+        }
+        map
+    }
+}
+
+// Runs both programs on fresh CPUs for up to `steps` ticks and compares the
+// data memory of every bank, the page pointers and the instruction memory
+// cells either program stored to. Only the PC is ignored, since equivalent
+// programs may be laid out differently.
+pub fn run_equivalent(a: &[u8], b: &[u8], steps: u64) -> Result<bool, LengthMismatch> {
+    let mut cpu_a = fresh_cpu(a)?;
+    let mut cpu_b = fresh_cpu(b)?;
+    let (start_a, start_b) = (cpu_a.inst_mem.content, cpu_b.inst_mem.content);
+    run_steps(&mut cpu_a, steps);
+    run_steps(&mut cpu_b, steps);
+
+    let (start_a, start_b) = (start_a.as_flattened(), start_b.as_flattened());
+    let code_a = cpu_a.inst_mem.content.as_flattened();
+    let code_b = cpu_b.inst_mem.content.as_flattened();
+    let stores_match = (0..code_a.len()).all(|i| {
+        let untouched = code_a[i] == start_a[i] && code_b[i] == start_b[i];
+        untouched || code_a[i] == code_b[i]
+    });
+
+    Ok(stores_match
+        && cpu_a.data_mem.content == cpu_b.data_mem.content
+        && cpu_a.data_mem.pointer == cpu_b.data_mem.pointer
+        && cpu_a.inst_mem.pointer == cpu_b.inst_mem.pointer)
+}
+
+fn fresh_cpu(program: &[u8]) -> Result<CPU, LengthMismatch> {
+    let mut inst_mem = [0; 127];
+    if program.len() > inst_mem.len() {
+        return Err(LengthMismatch {
+            expected: inst_mem.len(),
+            found: program.len(),
+        });
+    }
+    inst_mem[..program.len()].copy_from_slice(program);
+    Ok(CPU::new(inst_mem, Vec::new()))
+}
+
+fn run_steps(cpu: &mut CPU, steps: u64) {
+    for _ in 0..steps {
+        if cpu.tick() == Halted::Halted {
+            break;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Radix {
+    Hex,
+    // For display only, operands lose the `#` the assembler reads as hex
+    Decimal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisasmOptions {
+    pub addresses: bool,
+    pub bytes: bool,
+    pub radix: Radix,
+}
+
+impl Default for DisasmOptions {
+    fn default() -> Self {
+        Self {
+            addresses: true,
+            bytes: false,
+            radix: Radix::Hex,
+        }
+    }
+}
+
+impl DisasmOptions {
+    fn operand(&self, value: u8) -> String {
+        match self.radix {
+            Radix::Hex => format!("#{value:02X}"),
+            Radix::Decimal => value.to_string(),
+        }
+    }
+
+    fn column(&self, value: u8) -> String {
+        match self.radix {
+            Radix::Hex => format!("{value:02X}"),
+            Radix::Decimal => format!("{value:3}"),
+        }
+    }
+}
+
+pub fn disassemble(program: &[u8], options: &DisasmOptions, table: &OpCodeTable) -> String {
+    let mut disassembler = Disassembler::new(*options, table);
+    let mut output: String = disassembler.feed(program).concat();
+    output.extend(disassembler.finish());
+    output
+}
+
+// Disassembles bytes as they arrive, holding back a partial instruction
+// until the rest of it is fed in
+pub struct Disassembler {
+    options: DisasmOptions,
+    table: OpCodeTable,
+    pending: Vec<u8>,
+    addr: u8,
+}
+
+impl Disassembler {
+    pub fn new(options: DisasmOptions, table: &OpCodeTable) -> Self {
+        Self {
+            options,
+            table: *table,
+            pending: Vec::with_capacity(3),
+            addr: 0,
+        }
+    }
+
+    // Returns one line per instruction completed by `bytes`
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &byte in bytes {
+            self.pending.push(byte);
+            if self.pending.len() == 3 {
+                lines.push(self.line());
+                self.pending.clear();
+                self.addr = self.addr.wrapping_add(3);
+            }
+        }
+        lines
+    }
+
+    // Marks a trailing partial instruction as truncated
+    pub fn finish(self) -> Option<String> {
+        (!self.pending.is_empty()).then(|| self.line())
+    }
+
+    fn line(&self) -> String {
+        let options = &self.options;
+        let mut output = String::new();
+        if options.addresses {
+            write!(output, "{}: ", options.column(self.addr)).unwrap();
+        }
+        if options.bytes {
+            for byte in &self.pending {
+                write!(output, "{} ", options.column(*byte)).unwrap();
+            }
+        }
+        match self.pending[..] {
+            [a, b, c] => {
+                let inst = Instruction::decode_with([a, b, c], &self.table);
+                write!(output, "{}", inst.code.mnemonic()).unwrap();
+                if inst.options.encode() != 0 {
+                    write!(output, " :{:04b}", inst.options.encode()).unwrap();
+                }
+                let (arg1, arg2) = (inst.arg1.address(), inst.arg2.address());
+                write!(
+                    output,
+                    " {} {}",
+                    options.operand(arg1),
+                    options.operand(arg2)
+                )
+                .unwrap();
+            }
+            _ => write!(output, "<truncated>").unwrap(),
+        }
+        writeln!(output).unwrap();
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assembler,
+        devices::{Device, DeviceError, FaultInjectionDevice, RomDevice},
+    };
+
+    fn load_code(code: &[u8]) -> CPU {
+        let mut inst_mem = [0; 127];
+        inst_mem[..code.len()].copy_from_slice(code);
+        CPU::new(inst_mem, Vec::new())
+    }
+
+    fn load(source: &str) -> CPU {
+        let code = assembler::assemble(source).unwrap();
+        CPU::with_image(&code, &[], Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn state_report_shows_registers_memory_and_pc() {
+        // NOOP #2A #00, COPY #01 #81
+        let mut cpu = load_code(&[0x00, 0x2A, 0x00, 0x0C, 0x01, 0x81]);
+        cpu.tick();
+        cpu.tick();
+
+        let report = cpu.state_report();
+        assert!(report.contains("PC:          0x06"));
+        assert!(report.contains("Cycles:      2"));
+        assert!(report.contains("80: 00 2A 00 00 00 00 00 00"));
+        assert!(report.contains("  03: COPY #01 #81"));
+        assert!(report.contains("> 06: NOOP #00 #00"));
+    }
+
+    #[test]
+    fn equivalent_programs_compare_equal() {
+        // NOOP #06 #07, COPY #01 #80, MUL #80 #02
+        let multiply = [0x00, 0x06, 0x07, 0x0C, 0x01, 0x80, 0x06, 0x80, 0x02];
+        // NOOP #06 #00, NOOP #00 #00, COPY #01 #80, COPY #01 #81,
+        // 6 times ADD #80 #81, COPY #05 #81
+        let mut adds = vec![0x00, 0x06, 0x00, 0x00, 0x00, 0x00];
+        adds.extend([0x0C, 0x01, 0x80, 0x0C, 0x01, 0x81]);
+        adds.extend([0x04, 0x80, 0x81].repeat(6));
+        adds.extend([0x0C, 0x05, 0x81]);
+        assert_eq!(run_equivalent(&multiply, &adds, 11), Ok(true));
+
+        // NOOP #06 #07, COPY #02 #80
+        let different = [0x00, 0x06, 0x07, 0x0C, 0x02, 0x80];
+        assert_eq!(run_equivalent(&multiply, &different, 11), Ok(false));
+    }
+
+    #[test]
+    fn stores_to_instruction_memory_are_compared() {
+        // NOOP #06 #00, COPY #01 #80
+        let plain = [0x00, 0x06, 0x00, 0x0C, 0x01, 0x80];
+        // NOOP #06 #00, COPY #01 #80, COPY #01 #7B
+        let patching = [0x00, 0x06, 0x00, 0x0C, 0x01, 0x80, 0x0C, 0x01, 0x7B];
+        assert_eq!(run_equivalent(&plain, &patching, 3), Ok(false));
+    }
+
+    #[test]
+    fn oversized_programs_are_rejected() {
+        let error = run_equivalent(&[0; 128], &[], 1).unwrap_err();
+        assert_eq!(error.found, 128);
+    }
+
+    #[test]
+    fn hex_disassembly_reassembles() {
+        let program = assembler::assemble(include_str!("../fixtures/subroutine.ysm")).unwrap();
+        let options = DisasmOptions {
+            addresses: false,
+            ..DisasmOptions::default()
+        };
+        let source = disassemble(&program, &options, &OpCodeTable::default());
+        assert!(source.contains("DIV :1000 #80 #05"));
+        assert_eq!(assembler::assemble(&source).unwrap(), program);
+    }
+
+    #[test]
+    fn disassembly_formats() {
+        let program = [0x0C, 0x01, 0x80, 0x85];
+        assert_eq!(
+            disassemble(&program, &DisasmOptions::default(), &OpCodeTable::default()),
+            "00: COPY #01 #80\n03: <truncated>\n"
+        );
+        let options = DisasmOptions {
+            addresses: true,
+            bytes: true,
+            radix: Radix::Decimal,
+        };
+        assert_eq!(
+            disassemble(&program, &options, &OpCodeTable::default()),
+            "  0:  12   1 128 COPY 1 128\n  3: 133 <truncated>\n"
+        );
+    }
+
+    // Claims no addresses at all
+    struct Empty;
+
+    impl Device for Empty {
+        fn load(&mut self, addr: u8) -> Result<u8, DeviceError> {
+            Err(DeviceError::WriteOnly(addr))
+        }
+
+        fn push(&mut self, addr: u8, _data: u8) -> Result<(), DeviceError> {
+            Err(DeviceError::ReadOnly(addr))
+        }
+
+        fn address(&self) -> u8 {
+            0
+        }
+
+        fn size(&self) -> u8 {
+            0
+        }
+    }
+
+    #[test]
+    fn memory_map_lists_devices_and_gaps() {
+        let devices: Vec<Box<dyn Device>> = vec![
+            Box::new(RomDevice::new(200, vec![0; 4]).unwrap()),
+            Box::new(Empty),
+        ];
+        let cpu = CPU::new([0; 127], devices);
+        let map = cpu.memory_map();
+        assert_eq!(
+            map.lines().collect::<Vec<_>>(),
+            [
+                "  0-  0 Program Counter",
+                "  1-126 Instruction Memory",
+                "127-127 unmapped",
+                "128-191 Data Memory",
+                "192-192 Goto Page (Instructions)",
+                "193-193 Goto Page (Data)",
+                "194-199 unmapped",
+                "200-203 ROM",
+                "204-255 unmapped",
+            ]
+        );
+    }
+
+    #[test]
+    fn streaming_disassembly_matches_disassemble() {
+        let program = assembler::assemble(include_str!("../fixtures/subroutine.ysm")).unwrap();
+        let options = DisasmOptions::default();
+        let mut disassembler = Disassembler::new(options, &OpCodeTable::default());
+        let mut lines = Vec::new();
+        for chunk in program.chunks(2) {
+            lines.extend(disassembler.feed(chunk));
+        }
+        assert_eq!(disassembler.finish(), None);
+        assert_eq!(
+            lines.concat(),
+            disassemble(&program, &options, &OpCodeTable::default())
+        );
+
+        let mut disassembler = Disassembler::new(options, &OpCodeTable::default());
+        assert!(disassembler.feed(&[0x0C, 0x01]).is_empty());
+        assert_eq!(disassembler.feed(&[0x80, 0x00]), ["00: COPY #01 #80\n"]);
+        assert_eq!(disassembler.finish().unwrap(), "03: <truncated>\n");
+    }
+
+    #[test]
+    fn step_detailed_records_writes() {
+        let mut cpu = load("NOOP #05 #00\nCOPY #01 #80");
+        cpu.step_detailed();
+        let record = cpu.step_detailed();
+        assert_eq!(record.pc, 3);
+        assert!(matches!(
+            record.instruction.unwrap().code,
+            crate::OpCode::Copy
+        ));
+        assert_eq!(
+            record.writes,
+            [MemWrite {
+                addr: 0x80,
+                old: Some(0),
+                new: 5
+            }]
+        );
+        assert_eq!(record.after.pc, 6);
+        assert_eq!(record.halted, Halted::Running);
+    }
+
+    #[test]
+    fn step_detailed_past_the_last_instruction_halts() {
+        for pc in [125, 126, 255] {
+            let mut cpu = CPU::default();
+            cpu.reg_zero = pc;
+            assert!(cpu.fetch().is_none());
+            let record = cpu.step_detailed();
+            assert!(record.instruction.is_none());
+            assert_eq!(record.halted, Halted::Halted);
+            assert_eq!(record.after, record.before);
+        }
+        let mut cpu = CPU::default();
+        cpu.reg_zero = 124;
+        assert!(cpu.fetch().is_some());
+    }
+
+    #[test]
+    fn dump_device_reads_its_whole_range() {
+        let devices: Vec<Box<dyn Device>> = vec![
+            Box::new(RomDevice::new(220, vec![4, 5, 6]).unwrap()),
+            Box::new(Empty),
+        ];
+        let mut cpu = CPU::new([0; 127], devices);
+        assert_eq!(cpu.dump_device(0), Some(Ok(vec![4, 5, 6])));
+        assert_eq!(cpu.dump_device(1), Some(Ok(vec![])));
+        assert_eq!(cpu.dump_device(2), None);
+        assert_eq!(cpu.cycles, 0);
+    }
+
+    #[test]
+    fn dump_device_reports_load_failures() {
+        let rom = Box::new(RomDevice::new(200, vec![1, 2]).unwrap());
+        let failing = FaultInjectionDevice::new(rom, 2, DeviceError::Failed(201));
+        let mut cpu = CPU::new([0; 127], vec![Box::new(failing)]);
+        assert_eq!(cpu.dump_device(0), Some(Err(DeviceError::Failed(201))));
+    }
+}
@@ -1,5 +1,443 @@
-pub trait Device {
-    fn load(&mut self, addr: u8) -> u8;
-    fn push(&mut self, addr: u8, data: u8);
+use std::{any::Any, error::Error, fmt, ops::Range};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    ReadOnly(u8),
+    WriteOnly(u8),
+    Failed(u8),
+}
+
+pub trait Device: Any {
+    fn load(&mut self, addr: u8) -> Result<u8, DeviceError>;
+    fn push(&mut self, addr: u8, data: u8) -> Result<(), DeviceError>;
     fn address(&self) -> u8;
+    // Number of consecutive addresses, starting at `address`, the device answers to
+    fn size(&self) -> u8 {
+        1
+    }
+    // Extra cycles every load or push to the device costs
+    fn latency(&self) -> u64 {
+        0
+    }
+    fn name(&self) -> &str {
+        "Device"
+    }
+}
+
+// Two devices answer to `address`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceOverlap {
+    pub address: u8,
+}
+
+impl fmt::Display for DeviceOverlap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Two devices answer to address {:02X}", self.address)
+    }
+}
+
+impl Error for DeviceOverlap {}
+
+// The `size` addresses starting at `address` are empty or leave the device
+// space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutsideDeviceSpace {
+    pub address: u8,
+    pub size: usize,
+}
+
+fn check_device_space(address: u8, size: usize) -> Result<(), OutsideDeviceSpace> {
+    if address >= 194 && size > 0 && address as usize + size <= 256 {
+        Ok(())
+    } else {
+        Err(OutsideDeviceSpace { address, size })
+    }
+}
+
+// Devices in the order they were added, which is what device indices refer
+// to, plus the indices sorted by address for bus lookups
+#[derive(Default)]
+pub struct DeviceMap {
+    devices: Vec<Box<dyn Device>>,
+    by_address: Vec<usize>,
+}
+
+impl DeviceMap {
+    pub fn new(devices: Vec<Box<dyn Device>>) -> Result<DeviceMap, DeviceOverlap> {
+        let mut map = DeviceMap::default();
+        for device in devices {
+            map.push(device)?;
+        }
+        Ok(map)
+    }
+
+    pub fn push(&mut self, device: Box<dyn Device>) -> Result<(), DeviceOverlap> {
+        let new = span(device.as_ref());
+        for other in &self.devices {
+            let other = span(other.as_ref());
+            if new.start < other.end && other.start < new.end {
+                let address = new.start.max(other.start) as u8;
+                return Err(DeviceOverlap { address });
+            }
+        }
+        self.devices.push(device);
+        self.reindex();
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Box<dyn Device>> {
+        let device = (index < self.devices.len()).then(|| self.devices.remove(index))?;
+        self.reindex();
+        Some(device)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Box<dyn Device>> {
+        self.devices.get_mut(index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Device> {
+        self.devices.iter().map(|device| device.as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.devices.reserve(additional);
+        self.by_address.reserve(additional);
+    }
+
+    pub fn into_vec(self) -> Vec<Box<dyn Device>> {
+        self.devices
+    }
+
+    // The device answering to `addr`
+    pub fn find(&mut self, addr: u8) -> Option<&mut Box<dyn Device>> {
+        let slot = self
+            .by_address
+            .partition_point(|&index| self.devices[index].address() <= addr)
+            .checked_sub(1)?;
+        let device = &mut self.devices[self.by_address[slot]];
+        span(device.as_ref())
+            .contains(&(addr as u16))
+            .then_some(device)
+    }
+
+    // Devices answering to no address are left out of the index
+    fn reindex(&mut self) {
+        let devices = &self.devices;
+        self.by_address = (0..devices.len())
+            .filter(|&index| devices[index].size() > 0)
+            .collect();
+        self.by_address
+            .sort_by_key(|&index| devices[index].address());
+    }
+}
+
+fn span(device: &dyn Device) -> Range<u16> {
+    let start = device.address() as u16;
+    start..start + device.size() as u16
+}
+
+pub struct RomDevice {
+    address: u8,
+    data: Vec<u8>,
+    ignore_writes: bool,
+}
+
+impl RomDevice {
+    pub fn new(address: u8, data: Vec<u8>) -> Result<RomDevice, OutsideDeviceSpace> {
+        check_device_space(address, data.len())?;
+        Ok(RomDevice {
+            address,
+            data,
+            ignore_writes: false,
+        })
+    }
+
+    pub fn with_writes_ignored(
+        address: u8,
+        data: Vec<u8>,
+    ) -> Result<RomDevice, OutsideDeviceSpace> {
+        Ok(RomDevice {
+            ignore_writes: true,
+            ..RomDevice::new(address, data)?
+        })
+    }
+}
+
+impl Device for RomDevice {
+    fn load(&mut self, addr: u8) -> Result<u8, DeviceError> {
+        addr.checked_sub(self.address)
+            .and_then(|offset| self.data.get(offset as usize))
+            .copied()
+            .ok_or(DeviceError::Failed(addr))
+    }
+
+    fn push(&mut self, addr: u8, _data: u8) -> Result<(), DeviceError> {
+        if self.ignore_writes {
+            Ok(())
+        } else {
+            Err(DeviceError::ReadOnly(addr))
+        }
+    }
+
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn size(&self) -> u8 {
+        self.data.len() as u8
+    }
+
+    fn name(&self) -> &str {
+        "ROM"
+    }
+}
+
+// Read-only window onto host state, a load at `address + selector` returns
+// `query(selector)`
+pub struct HostQuery {
+    address: u8,
+    size: u8,
+    query: Box<dyn FnMut(u8) -> u8>,
+}
+
+impl HostQuery {
+    pub fn new(
+        address: u8,
+        size: u8,
+        query: impl FnMut(u8) -> u8 + 'static,
+    ) -> Result<HostQuery, OutsideDeviceSpace> {
+        check_device_space(address, size as usize)?;
+        Ok(HostQuery {
+            address,
+            size,
+            query: Box::new(query),
+        })
+    }
+}
+
+impl Device for HostQuery {
+    fn load(&mut self, addr: u8) -> Result<u8, DeviceError> {
+        match addr.checked_sub(self.address) {
+            Some(selector) if selector < self.size => Ok((self.query)(selector)),
+            _ => Err(DeviceError::Failed(addr)),
+        }
+    }
+
+    fn push(&mut self, addr: u8, _data: u8) -> Result<(), DeviceError> {
+        Err(DeviceError::ReadOnly(addr))
+    }
+
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn size(&self) -> u8 {
+        self.size
+    }
+
+    fn name(&self) -> &str {
+        "Host query"
+    }
+}
+
+// Forwards to `inner` but fails its `fail_at`th access, counting from 1 over
+// loads and pushes together
+pub struct FaultInjectionDevice {
+    inner: Box<dyn Device>,
+    fail_at: u64,
+    error: DeviceError,
+    accesses: u64,
+}
+
+impl FaultInjectionDevice {
+    pub fn new(inner: Box<dyn Device>, fail_at: u64, error: DeviceError) -> FaultInjectionDevice {
+        FaultInjectionDevice {
+            inner,
+            fail_at,
+            error,
+            accesses: 0,
+        }
+    }
+
+    fn should_fail(&mut self) -> bool {
+        self.accesses += 1;
+        self.accesses == self.fail_at
+    }
+}
+
+impl Device for FaultInjectionDevice {
+    fn load(&mut self, addr: u8) -> Result<u8, DeviceError> {
+        if self.should_fail() {
+            return Err(self.error);
+        }
+        self.inner.load(addr)
+    }
+
+    fn push(&mut self, addr: u8, data: u8) -> Result<(), DeviceError> {
+        if self.should_fail() {
+            return Err(self.error);
+        }
+        self.inner.push(addr, data)
+    }
+
+    fn address(&self) -> u8 {
+        self.inner.address()
+    }
+
+    fn size(&self) -> u8 {
+        self.inner.size()
+    }
+
+    fn latency(&self) -> u64 {
+        self.inner.latency()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulation::{Halted, CPU};
+
+    fn load_code(code: &[u8], devices: Vec<Box<dyn Device>>) -> CPU {
+        let mut inst_mem = [0; 127];
+        inst_mem[..code.len()].copy_from_slice(code);
+        CPU::new(inst_mem, devices)
+    }
+
+    #[test]
+    fn rom_is_readable_through_the_bus() {
+        let rom = RomDevice::new(200, vec![7, 9]).unwrap();
+        // COPY #C8 #80, COPY #C9 #81
+        let code = [0x0C, 0xC8, 0x80, 0x0C, 0xC9, 0x81];
+        let mut cpu = load_code(&code, vec![Box::new(rom)]);
+        cpu.tick();
+        cpu.tick();
+        assert_eq!(&cpu.data_mem[0..2], &[7, 9]);
+    }
+
+    #[test]
+    fn rom_rejects_or_ignores_writes() {
+        let mut rom = RomDevice::new(200, vec![1]).unwrap();
+        assert_eq!(rom.push(200, 5), Err(DeviceError::ReadOnly(200)));
+        let mut rom = RomDevice::with_writes_ignored(200, vec![1]).unwrap();
+        assert_eq!(rom.push(200, 5), Ok(()));
+        assert_eq!(rom.load(200), Ok(1));
+        assert_eq!(rom.load(201), Err(DeviceError::Failed(201)));
+    }
+
+    #[test]
+    fn rom_outside_device_space_is_rejected() {
+        let error = RomDevice::new(128, vec![1]).err();
+        assert_eq!(
+            error,
+            Some(OutsideDeviceSpace {
+                address: 128,
+                size: 1
+            })
+        );
+        assert!(RomDevice::new(250, vec![0; 7]).is_err());
+        assert!(RomDevice::new(200, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn failed_device_access_is_recorded() {
+        let rom = RomDevice::new(200, vec![1]).unwrap();
+        // COPY #80 #C8
+        let mut cpu = load_code(&[0x0C, 0x80, 0xC8], vec![Box::new(rom)]);
+        cpu.tick();
+        assert_eq!(cpu.device_error, Some(DeviceError::ReadOnly(200)));
+    }
+
+    #[test]
+    fn unmapped_device_address_is_not_a_device_error() {
+        // COPY #D0 #80
+        let mut cpu = load_code(&[0x0C, 0xD0, 0x80], Vec::new());
+        cpu.tick();
+        assert_eq!(cpu.device_error, None);
+    }
+
+    #[test]
+    fn host_query_answers_loads_by_selector() {
+        let query = HostQuery::new(210, 4, |selector| selector * 2 + 1).unwrap();
+        // COPY #D3 #80, COPY :1000 #80 #D3
+        let code = [0x0C, 0xD3, 0x80, 0x8C, 0x80, 0xD3];
+        let mut cpu = load_code(&code, vec![Box::new(query)]);
+        cpu.tick();
+        assert_eq!(cpu.data_mem[0], 3);
+        assert_eq!(cpu.tick(), Halted::Halted);
+        assert_eq!(cpu.device_error, Some(DeviceError::ReadOnly(211)));
+    }
+
+    #[test]
+    fn host_query_outside_device_space_is_rejected() {
+        let error = HostQuery::new(250, 10, |_| 0).err();
+        assert_eq!(
+            error,
+            Some(OutsideDeviceSpace {
+                address: 250,
+                size: 10
+            })
+        );
+        assert!(HostQuery::new(200, 0, |_| 0).is_err());
+
+        let mut query = HostQuery::new(200, 2, |selector| selector).unwrap();
+        assert_eq!(query.load(201), Ok(1));
+        assert_eq!(query.load(202), Err(DeviceError::Failed(202)));
+    }
+
+    #[test]
+    fn device_map_keeps_insertion_order() {
+        let mut map = DeviceMap::new(vec![
+            Box::new(RomDevice::new(240, vec![1]).unwrap()),
+            Box::new(RomDevice::new(200, vec![2, 3]).unwrap()),
+        ])
+        .unwrap();
+        let addresses: Vec<_> = map.iter().map(|device| device.address()).collect();
+        assert_eq!(addresses, [240, 200]);
+        assert_eq!(map.find(201).unwrap().load(201), Ok(3));
+        assert_eq!(map.find(240).unwrap().load(240), Ok(1));
+        assert!(map.find(202).is_none());
+
+        assert_eq!(map.remove(0).unwrap().address(), 240);
+        assert!(map.find(240).is_none());
+        assert_eq!(map.get_mut(0).unwrap().address(), 200);
+    }
+
+    #[test]
+    fn device_map_rejects_overlaps() {
+        let mut map =
+            DeviceMap::new(vec![Box::new(RomDevice::new(200, vec![0; 4]).unwrap())]).unwrap();
+        let error = map.push(Box::new(RomDevice::new(203, vec![0; 2]).unwrap()));
+        assert_eq!(error, Err(DeviceOverlap { address: 203 }));
+        let error = map.push(Box::new(RomDevice::new(196, vec![0; 5]).unwrap()));
+        assert_eq!(error, Err(DeviceOverlap { address: 200 }));
+        assert_eq!(map.len(), 1);
+        assert!(map
+            .push(Box::new(RomDevice::new(204, vec![0]).unwrap()))
+            .is_ok());
+    }
+
+    #[test]
+    fn fault_injection_fails_one_access() {
+        let rom = Box::new(RomDevice::new(200, vec![7]).unwrap());
+        let failing = FaultInjectionDevice::new(rom, 2, DeviceError::Failed(200));
+        // COPY #C8 #80, COPY #C8 #81, COPY #C8 #82
+        let code = [0x0C, 0xC8, 0x80, 0x0C, 0xC8, 0x81, 0x0C, 0xC8, 0x82];
+        let mut cpu = load_code(&code, vec![Box::new(failing)]);
+        cpu.run(3);
+        assert_eq!(&cpu.data_mem[0..3], &[7, 0, 7]);
+        assert_eq!(cpu.device_error, Some(DeviceError::Failed(200)));
+    }
 }
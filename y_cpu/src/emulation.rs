@@ -1,47 +1,788 @@
-use crate::{banking::Banker, devices::Device, symbols::Symbol, Instruction, OpOptions};
+use std::{
+    any::Any,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    io::{self, Read, Write},
+    mem,
+    ops::RangeInclusive,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    aliasing::{AliasError, AliasTable},
+    alu::{Alu, StandardAlu},
+    banking::Banker,
+    debug::DebugInfo,
+    devices::{Device, DeviceError, DeviceMap, DeviceOverlap},
+    journal::JournalStep,
+    profiling::{self, Access, CoverageReport, ProfileReport, Region, TraceEntry},
+    program,
+    protection::{AccessKind, Permissions, ProtectionFault},
+    symbols::Symbol,
+    Instruction, OpCodeTable, OpOptions,
+};
 
 pub struct CPU {
     pub reg_zero: u8,
+    pub cycles: u64,
     pub inst_mem: Banker<[u8; 127]>,
     pub data_mem: Banker<[u8; 64]>,
-    pub devices: Vec<Box<dyn Device>>,
+    pub(crate) devices: DeviceMap,
+    pub profiler: Option<ProfileReport>,
+    pub device_error: Option<DeviceError>,
+    pub data_written: Option<Box<[[bool; 64]; 256]>>,
+    pub accesses: Option<Vec<Access>>,
+    pub trap_pc_overflow: bool,
+    // Target of the jump trap_pc_overflow rejected during the last tick
+    pub illegal_jump: Option<u8>,
+    pub aliases: AliasTable,
+    pub detect_livelock: bool,
+    pub data_reads_from_code: bool,
+    pub end_of_program: EndOfProgram,
+    // PC that ran past the last instruction under EndOfProgram::Error
+    pub overrun: Option<u8>,
+    pub fault_handler: Option<FaultHandler>,
+    pub trap_vectors: TrapVectors,
+    pub trace: Option<Vec<TraceEntry>>,
+    pub trace_filter: Option<TraceFilter>,
+    pub journal: Option<Vec<JournalStep>>,
+    pub program_len: Option<usize>,
+    pub strict: bool,
+    // PC strict mode halted on after it ran into the padding
+    pub padding_pc: Option<u8>,
+    pub executed: Option<Box<[[bool; 127]; 256]>>,
+    pub trap_misaligned: bool,
+    // PC trap_misaligned halted on
+    pub misaligned_pc: Option<u8>,
+    pub opcode_table: OpCodeTable,
+    pub debug_info: Option<DebugInfo>,
+    pub reg_watches: Vec<(Reg, u8)>,
+    pub alu: Box<dyn Alu>,
+    pub max_devices: Option<usize>,
+    pub protection: BTreeMap<Region, Permissions>,
+    pub protection_fault: Option<ProtectionFault>,
+    pub write_log: Option<Box<dyn Write>>,
+    pub pause: Option<Arc<AtomicBool>>,
+    // Keyed by instruction bank and address. Stores through the bus keep it
+    // up to date, direct edits of inst_mem or the opcode table need a clear.
+    pub decode_cache: Option<HashMap<(u8, u8), Instruction>>,
+    // Hash of every memory bank, kept up to date by stores while run looks
+    // for livelocks
+    memory_hash: Option<u64>,
+}
+
+// Bit 0: loading the first argument failed, bit 1: loading the second
+// argument failed, bit 2: storing the result failed, bit 3: division by zero
+#[derive(Debug, Clone)]
+pub struct Fault {
+    pub instruction: Instruction,
+    pub errors: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    // Fall back to the halt-on-error option of the instruction
+    Propagate,
+    Resume,
+    Halt,
+}
+
+// Addresses execution continues at when a fault of the given class occurs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrapVectors {
+    pub illegal_load: Option<u8>,
+    pub illegal_store: Option<u8>,
+    pub divide_by_zero: Option<u8>,
+}
+
+impl TrapVectors {
+    fn vector(&self, errors: u8) -> Option<u8> {
+        let pick = |bits: u8, vector: Option<u8>| vector.filter(|_| errors & bits != 0);
+        pick(0b1000, self.divide_by_zero)
+            .or(pick(0b0100, self.illegal_store))
+            .or(pick(0b0011, self.illegal_load))
+    }
 }
 
+pub type FaultHandler = Box<dyn FnMut(&Fault, &mut CPU) -> FaultAction>;
+
+pub type TraceFilter = Box<dyn Fn(&Instruction) -> bool>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthMismatch {
+    pub expected: usize,
+    pub found: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InternalPanic(pub String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocateError {
+    // Instructions have to start at multiples of 3
+    MisalignedBase(u8),
+    // A relocation offset points past the end of the program
+    Offset(u8),
+    Length(LengthMismatch),
+    Overlap(DeviceOverlap),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    Length(LengthMismatch),
+    Overlap(DeviceOverlap),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Halted {
     Running,
     Halted,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddDeviceError {
+    TooMany { max: usize },
+    Overlap(DeviceOverlap),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndOfProgram {
+    // Halts and records the PC in CPU::overrun, so running off the end can be
+    // told apart from a deliberate halt
+    Error,
+    Halt,
+    Wrap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    Halted,
+    OutOfFuel,
+    // The exact machine state seen `length` ticks earlier came back
+    Livelock { length: u64 },
+    // A tick left `reg` holding a watched value
+    Watch { reg: Reg, value: u8 },
+    Paused,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Zero,
+    InstPage,
+    DataPage,
+}
+
+impl Reg {
+    pub fn address(self) -> u8 {
+        match self {
+            Reg::Zero => 0,
+            Reg::InstPage => 192,
+            Reg::DataPage => 193,
+        }
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        CPU::new([0; 127], Vec::new())
+    }
+}
+
 impl CPU {
+    // Panics if two of `devices` answer to the same address, try_new and
+    // add_device report that as an error instead
     pub fn new(inst_mem: [u8; 127], devices: Vec<Box<dyn Device>>) -> CPU {
-        let mut mapped_devices = Vec::with_capacity(62);
-        for device in devices.into_iter() {
-            let address = device.address() as usize - 194;
-            mapped_devices[address] = device;
-        }
-        CPU {
+        CPU::try_new(inst_mem, devices).expect("Devices overlap")
+    }
+
+    pub fn try_new(
+        inst_mem: [u8; 127],
+        devices: Vec<Box<dyn Device>>,
+    ) -> Result<CPU, DeviceOverlap> {
+        let devices = DeviceMap::new(devices)?;
+        Ok(CPU {
             reg_zero: 0,
+            cycles: 0,
             inst_mem: Banker::new(inst_mem),
             data_mem: Banker::new([0; 64]),
-            devices: mapped_devices,
+            devices,
+            profiler: None,
+            device_error: None,
+            data_written: None,
+            accesses: None,
+            trap_pc_overflow: false,
+            illegal_jump: None,
+            aliases: AliasTable::default(),
+            detect_livelock: false,
+            data_reads_from_code: true,
+            end_of_program: EndOfProgram::Halt,
+            overrun: None,
+            fault_handler: None,
+            trap_vectors: TrapVectors::default(),
+            trace: None,
+            trace_filter: None,
+            journal: None,
+            program_len: None,
+            strict: false,
+            padding_pc: None,
+            executed: None,
+            trap_misaligned: false,
+            misaligned_pc: None,
+            opcode_table: OpCodeTable::default(),
+            debug_info: None,
+            reg_watches: Vec::new(),
+            alu: Box::new(StandardAlu::default()),
+            max_devices: None,
+            protection: BTreeMap::new(),
+            protection_fault: None,
+            write_log: None,
+            pause: None,
+            decode_cache: None,
+            memory_hash: None,
+        })
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R, devices: Vec<Box<dyn Device>>) -> io::Result<CPU> {
+        let mut inst_mem = [0; 127];
+        let length = program::read_header(&mut reader, inst_mem.len())?;
+        reader.read_exact(&mut inst_mem[..length])?;
+        let cpu = CPU::try_new(inst_mem, devices)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        Ok(CPU {
+            program_len: Some(length),
+            ..cpu
+        })
+    }
+
+    // Loads `code` into every instruction bank and `data_init` into the start
+    // of data bank 0, the rest of memory is zeroed
+    pub fn with_image(
+        code: &[u8],
+        data_init: &[u8],
+        devices: Vec<Box<dyn Device>>,
+    ) -> Result<CPU, ImageError> {
+        let mut inst_mem = [0; 127];
+        if code.len() > inst_mem.len() {
+            return Err(ImageError::Length(LengthMismatch {
+                expected: inst_mem.len(),
+                found: code.len(),
+            }));
+        }
+        inst_mem[..code.len()].copy_from_slice(code);
+
+        let mut cpu = CPU {
+            program_len: Some(code.len()),
+            ..CPU::try_new(inst_mem, devices).map_err(ImageError::Overlap)?
+        };
+        let data_bank = &mut cpu.data_mem.content[0];
+        if data_init.len() > data_bank.len() {
+            return Err(ImageError::Length(LengthMismatch {
+                expected: data_bank.len(),
+                found: data_init.len(),
+            }));
+        }
+        data_bank[..data_init.len()].copy_from_slice(data_init);
+        Ok(cpu)
+    }
+
+    // Loads `program` at `base` in every instruction bank and shifts the
+    // operand bytes at the `relocations` offsets by `base`. Execution starts
+    // at the first instruction of the program.
+    pub fn load_relocatable(
+        program: &[u8],
+        base: u8,
+        relocations: &[u8],
+        devices: Vec<Box<dyn Device>>,
+    ) -> Result<CPU, RelocateError> {
+        let mut inst_mem = [0; 127];
+        let start = base as usize;
+        if !base.is_multiple_of(3) {
+            return Err(RelocateError::MisalignedBase(base));
+        }
+        if start + program.len() > inst_mem.len() {
+            return Err(RelocateError::Length(LengthMismatch {
+                expected: inst_mem.len() - start.min(inst_mem.len()),
+                found: program.len(),
+            }));
+        }
+        if let Some(&offset) = relocations
+            .iter()
+            .find(|&&offset| offset as usize >= program.len())
+        {
+            return Err(RelocateError::Offset(offset));
+        }
+        let code = &mut inst_mem[start..start + program.len()];
+        code.copy_from_slice(program);
+        for &offset in relocations {
+            code[offset as usize] = code[offset as usize].wrapping_add(base);
+        }
+        Ok(CPU {
+            reg_zero: base,
+            program_len: Some(start + program.len()),
+            ..CPU::try_new(inst_mem, devices).map_err(RelocateError::Overlap)?
+        })
+    }
+
+    // All data memory banks in order, 64 bytes each
+    pub fn data_mem_bytes(&self) -> Vec<u8> {
+        self.data_mem.content.concat()
+    }
+
+    pub fn set_data_mem_bytes(&mut self, bytes: &[u8]) -> Result<(), LengthMismatch> {
+        let expected = self.data_mem.content.len() * 64;
+        if bytes.len() != expected {
+            return Err(LengthMismatch {
+                expected,
+                found: bytes.len(),
+            });
+        }
+        for (bank, chunk) in self.data_mem.content.iter_mut().zip(bytes.chunks_exact(64)) {
+            bank.copy_from_slice(chunk);
+        }
+        Ok(())
+    }
+
+    // Caps add_device at `max` devices and reserves room for all of them
+    pub fn set_max_devices(&mut self, max: usize) {
+        self.max_devices = Some(max);
+        self.devices.reserve(max.saturating_sub(self.devices.len()));
+    }
+
+    // Devices keep the index they were added at until an earlier one is
+    // removed
+    pub fn add_device(&mut self, device: Box<dyn Device>) -> Result<(), AddDeviceError> {
+        if let Some(max) = self.max_devices.filter(|&max| self.devices.len() >= max) {
+            return Err(AddDeviceError::TooMany { max });
+        }
+        self.devices.push(device).map_err(AddDeviceError::Overlap)
+    }
+
+    pub fn remove_device(&mut self, index: usize) -> Option<Box<dyn Device>> {
+        self.devices.remove(index)
+    }
+
+    pub fn devices(&self) -> &DeviceMap {
+        &self.devices
+    }
+
+    pub fn device_mut(&mut self, index: usize) -> Option<&mut dyn Device> {
+        Some(self.devices.get_mut(index)?.as_mut())
+    }
+
+    pub fn device_downcast_mut<T: Device>(&mut self, index: usize) -> Option<&mut T> {
+        let device: &mut dyn Any = self.devices.get_mut(index)?.as_mut();
+        device.downcast_mut()
+    }
+
+    pub fn clear_devices(&mut self) -> Vec<Box<dyn Device>> {
+        mem::take(&mut self.devices).into_vec()
+    }
+
+    pub fn set_fault_handler(
+        &mut self,
+        handler: impl FnMut(&Fault, &mut CPU) -> FaultAction + 'static,
+    ) {
+        self.fault_handler = Some(Box::new(handler));
+    }
+
+    // Profiling is off by default so the hot path only pays for a `None` check
+    pub fn enable_profiling(&mut self) {
+        self.profiler.get_or_insert_with(ProfileReport::default);
+    }
+
+    pub fn disable_profiling(&mut self) {
+        self.profiler = None;
+    }
+
+    pub fn profile(&self) -> ProfileReport {
+        self.profiler.clone().unwrap_or_default()
+    }
+
+    // Executed instructions per opcode, counted while profiling is enabled.
+    // Keys are OpCode values, independent of the opcode table in use.
+    pub fn opcode_histogram(&self) -> BTreeMap<u8, u64> {
+        self.profiler
+            .as_ref()
+            .map(|profiler| profiler.opcodes.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn read_reg(&self, reg: Reg) -> u8 {
+        match reg {
+            Reg::Zero => self.reg_zero,
+            Reg::InstPage => self.inst_mem.pointer,
+            Reg::DataPage => self.data_mem.pointer,
+        }
+    }
+
+    // With trap_pc_overflow set, a PC outside instruction memory is rejected
+    // like a jump to it: the PC keeps its value and illegal_jump records it
+    pub fn write_reg(&mut self, reg: Reg, value: u8) {
+        match reg {
+            Reg::Zero if self.trap_pc_overflow && !self.pc_in_range(value) => {
+                self.illegal_jump = Some(value);
+            }
+            Reg::Zero => self.reg_zero = value,
+            Reg::InstPage => self.inst_mem.pointer = value,
+            Reg::DataPage => self.data_mem.pointer = value,
         }
     }
 
+    // Zeroes the PC and both page pointers, memory, devices and the cycle
+    // counter are left as they are
+    pub fn reset_registers(&mut self) {
+        for reg in [Reg::Zero, Reg::InstPage, Reg::DataPage] {
+            self.write_reg(reg, 0);
+        }
+    }
+
+    // Setting the returned flag from any thread makes run return Paused
+    // before its next tick. It has to be cleared again to resume.
+    pub fn pause_handle(&mut self) -> Arc<AtomicBool> {
+        self.pause
+            .get_or_insert_with(|| Arc::new(AtomicBool::new(false)))
+            .clone()
+    }
+
+    // Makes run stop once a tick leaves `reg` equal to `value`
+    pub fn watch_reg(&mut self, reg: Reg, value: u8) {
+        self.reg_watches.push((reg, value));
+    }
+
+    // Redirects accesses to `from` onto the window starting at `to`
+    pub fn add_alias(&mut self, from: RangeInclusive<u8>, to: u8) -> Result<(), AliasError> {
+        self.aliases.add(from, to)
+    }
+
+    pub fn enable_access_log(&mut self) {
+        self.accesses.get_or_insert_with(Vec::new);
+    }
+
+    pub fn access_log(&self) -> &[Access] {
+        self.accesses.as_deref().unwrap_or_default()
+    }
+
+    pub fn enable_trace(&mut self) {
+        self.trace.get_or_insert_with(Vec::new);
+    }
+
+    // Only instructions the filter accepts are added to the trace
+    pub fn set_trace_filter(&mut self, filter: impl Fn(&Instruction) -> bool + 'static) {
+        self.trace_filter = Some(Box::new(filter));
+    }
+
+    pub fn trace_csv(&self) -> String {
+        profiling::trace_csv(self.trace.as_deref().unwrap_or_default())
+    }
+
+    pub fn enable_coverage(&mut self) {
+        self.executed
+            .get_or_insert_with(|| Box::new([[false; 127]; 256]));
+    }
+
+    // Instructions are counted at every third address up to the program
+    // length, or up to the last complete instruction if it is unknown
+    pub fn coverage(&self, bank: u8) -> CoverageReport {
+        let mut report = CoverageReport::default();
+        let Some(executed) = &self.executed else {
+            return report;
+        };
+        let end = self.program_len.unwrap_or(125).min(125);
+        for addr in (0..end as u8).step_by(3) {
+            if executed[bank as usize][addr as usize] {
+                report.executed.insert(addr);
+            } else {
+                report.uncovered.insert(addr);
+            }
+        }
+        // Execution may also have started at unaligned addresses
+        for (addr, _) in executed[bank as usize]
+            .iter()
+            .enumerate()
+            .filter(|(_, hit)| **hit)
+        {
+            report.executed.insert(addr as u8);
+        }
+        report
+    }
+
+    // Bytes of instruction memory after the program, falls back to what trim
+    // would find if the program length is unknown
+    pub fn free_space(&self) -> usize {
+        127usize.saturating_sub(self.program_len.unwrap_or_else(|| self.used_len()))
+    }
+
+    // Sets the program length to end after the last instruction that is not
+    // all zero padding, in any bank
+    pub fn trim(&mut self) {
+        self.program_len = Some(self.used_len());
+    }
+
+    fn used_len(&self) -> usize {
+        self.inst_mem
+            .content
+            .iter()
+            .filter_map(|bank| {
+                bank.chunks(3)
+                    .rposition(|inst| inst.iter().any(|&b| b != 0))
+            })
+            .max()
+            .map_or(0, |last| (last + 1) * 3)
+            .min(127)
+    }
+
+    // Treats data memory as uninitialized, so reading a cell before it has
+    // been written fails like an illegal load
+    pub fn poison_data_mem(&mut self) {
+        self.data_written = Some(Box::new([[false; 64]; 256]));
+    }
+
     pub fn tick(&mut self) -> Halted {
-        let inst = self.fetch();
+        self.overrun = None;
+        self.misaligned_pc = None;
+        self.padding_pc = None;
+        self.journal_step();
+        // The PC ran past the last complete instruction of the bank
+        if self.peek(self.reg_zero).is_none() {
+            match self.end_of_program {
+                EndOfProgram::Error => {
+                    self.overrun = Some(self.reg_zero);
+                    return Halted::Halted;
+                }
+                EndOfProgram::Halt => return Halted::Halted,
+                EndOfProgram::Wrap => self.reg_zero = 0,
+            }
+        }
+        // Instructions start at multiples of 3, anything else lands inside one
+        if self.trap_misaligned && !self.reg_zero.is_multiple_of(3) {
+            self.misaligned_pc = Some(self.reg_zero);
+            return Halted::Halted;
+        }
+        // In strict mode running into the zero padding after the program halts
+        // instead of executing it as NOOPs
+        if self.strict
+            && self
+                .program_len
+                .is_some_and(|len| self.reg_zero as usize >= len)
+        {
+            self.padding_pc = Some(self.reg_zero);
+            return Halted::Halted;
+        }
+        if !self.check_access(self.reg_zero, AccessKind::Execute) {
+            return Halted::Halted;
+        }
+        let Some(inst) = self.fetch_cached() else {
+            return Halted::Halted;
+        };
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record_fetch(inst.code);
+        }
+        if let Some(executed) = &mut self.executed {
+            executed[self.inst_mem.pointer as usize][self.reg_zero as usize] = true;
+        }
+        let traced = self
+            .trace_filter
+            .as_ref()
+            .is_none_or(|filter| filter(&inst));
+        if let Some(trace) = self.trace.as_mut().filter(|_| traced) {
+            trace.push(TraceEntry {
+                cycle: self.cycles,
+                pc: self.reg_zero,
+                opcode: inst.code,
+            });
+        }
+        self.cycles += 1;
         self.process(inst)
     }
 
-    pub fn fetch(&self) -> Instruction {
-        Instruction::decode([
-            self.inst_mem[self.reg_zero as usize],
-            self.inst_mem[(self.reg_zero + 1) as usize],
-            self.inst_mem[(self.reg_zero + 2) as usize],
-        ])
+    // Safety net for embedders: a panic inside the tick, for example from a
+    // device, fault handler or custom ALU, is returned instead of unwinding.
+    // The CPU may be left mid-instruction afterwards.
+    pub fn tick_guarded(&mut self) -> Result<Halted, InternalPanic> {
+        panic::catch_unwind(AssertUnwindSafe(|| self.tick())).map_err(|payload| {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            InternalPanic(message)
+        })
+    }
+
+    // Ticks until the CPU halts or `fuel` ticks have run. With `detect_livelock`
+    // set, a state that recurs is reported as a livelock. Device state is not
+    // part of the comparison, so programs that poll devices may be flagged.
+    // Each state is only compared against one saved state (Brent's algorithm),
+    // so a loop can take up to twice its length plus its lead-in to be noticed.
+    pub fn run(&mut self, fuel: u64) -> RunOutcome {
+        self.memory_hash = self.detect_livelock.then(|| self.full_memory_hash());
+        let outcome = self.run_ticks(fuel);
+        self.memory_hash = None;
+        outcome
+    }
+
+    fn run_ticks(&mut self, fuel: u64) -> RunOutcome {
+        let (mut saved, mut power, mut since) = (None, 1, 0);
+        for _ in 0..fuel {
+            if self
+                .pause
+                .as_ref()
+                .is_some_and(|pause| pause.load(Ordering::Relaxed))
+            {
+                return RunOutcome::Paused;
+            }
+            if let Some(hash) = self.livelock_hash() {
+                if saved == Some(hash) {
+                    return RunOutcome::Livelock { length: since };
+                }
+                if saved.is_none() || since == power {
+                    saved = Some(hash);
+                    power *= 2;
+                    since = 0;
+                }
+            }
+            if self.tick() == Halted::Halted {
+                return RunOutcome::Halted;
+            }
+            since += 1;
+            let hit = self
+                .reg_watches
+                .iter()
+                .find(|&&(reg, value)| self.read_reg(reg) == value);
+            if let Some(&(reg, value)) = hit {
+                return RunOutcome::Watch { reg, value };
+            }
+        }
+        RunOutcome::OutOfFuel
+    }
+
+    // Runs like run with a one-shot watch on the PC, so reaching `target`
+    // returns RunOutcome::Watch
+    pub fn run_to(&mut self, target: u8, fuel: u64) -> RunOutcome {
+        self.reg_watches.push((Reg::Zero, target));
+        let outcome = self.run(fuel);
+        self.reg_watches.pop();
+        outcome
+    }
+
+    // Covers the PC, the page pointers and every memory bank, but not the
+    // cycle counter or device state
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.reg_zero.hash(&mut hasher);
+        self.inst_mem.pointer.hash(&mut hasher);
+        self.inst_mem.content.hash(&mut hasher);
+        self.data_mem.pointer.hash(&mut hasher);
+        self.data_mem.content.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn livelock_hash(&self) -> Option<u64> {
+        let registers = u32::from_be_bytes([
+            2,
+            self.reg_zero,
+            self.inst_mem.pointer,
+            self.data_mem.pointer,
+        ]);
+        Some(self.memory_hash? ^ mix(registers as u64))
+    }
+
+    fn full_memory_hash(&self) -> u64 {
+        let inst = self
+            .inst_mem
+            .content
+            .iter()
+            .enumerate()
+            .flat_map(|(bank, cells)| {
+                let bank = bank as u8;
+                cells
+                    .iter()
+                    .enumerate()
+                    .map(move |(index, &value)| cell_hash(0, bank, index as u8, value))
+            });
+        let data = self
+            .data_mem
+            .content
+            .iter()
+            .enumerate()
+            .flat_map(|(bank, cells)| {
+                let bank = bank as u8;
+                cells
+                    .iter()
+                    .enumerate()
+                    .map(move |(index, &value)| cell_hash(1, bank, index as u8, value))
+            });
+        inst.chain(data).fold(0, |hash, cell| hash ^ cell)
+    }
+
+    // Swaps the old value of a cell for the new one in the memory hash
+    fn rehash(&mut self, region: u8, bank: u8, index: u8, old: u8, new: u8) {
+        if let Some(hash) = &mut self.memory_hash {
+            *hash ^= cell_hash(region, bank, index, old) ^ cell_hash(region, bank, index, new);
+        }
+    }
+
+    // The instruction at the PC, None once the PC is past the last complete
+    // instruction of the bank
+    pub fn fetch(&self) -> Option<Instruction> {
+        self.peek(self.reg_zero)
+    }
+
+    pub fn enable_decode_cache(&mut self) {
+        self.decode_cache.get_or_insert_with(HashMap::new);
+    }
+
+    pub fn clear_decode_cache(&mut self) {
+        if let Some(cache) = &mut self.decode_cache {
+            cache.clear();
+        }
+    }
+
+    fn fetch_cached(&mut self) -> Option<Instruction> {
+        let key = (self.inst_mem.pointer, self.reg_zero);
+        if let Some(inst) = self.decode_cache.as_ref().and_then(|cache| cache.get(&key)) {
+            return Some(inst.clone());
+        }
+        let inst = self.fetch()?;
+        if let Some(cache) = &mut self.decode_cache {
+            cache.insert(key, inst.clone());
+        }
+        Some(inst)
+    }
+
+    // Drops every cached instruction that covers `addr` in the current bank
+    fn invalidate_decode(&mut self, addr: u8) {
+        if let Some(cache) = &mut self.decode_cache {
+            let bank = self.inst_mem.pointer;
+            for start in addr.saturating_sub(2)..=addr {
+                cache.remove(&(bank, start));
+            }
+        }
+    }
+
+    // Decodes the instruction starting at `addr` without executing it
+    pub fn peek(&self, addr: u8) -> Option<Instruction> {
+        let addr = addr as usize;
+        if addr + 2 >= 127 {
+            return None;
+        }
+        Some(Instruction::decode_with(
+            [
+                self.inst_mem[addr],
+                self.inst_mem[addr + 1],
+                self.inst_mem[addr + 2],
+            ],
+            &self.opcode_table,
+        ))
     }
 
     fn process(&mut self, inst: Instruction) -> Halted {
         use crate::OpCode::*;
+        self.illegal_jump = None;
         let result: u8 = match inst.code {
             NoOp => 0,
             And => {
@@ -62,26 +803,34 @@ impl CPU {
             Add => {
                 let ((data1, result1), (data2, result2)) =
                     self.load_double_signed(&inst.arg1, &inst.arg2, &inst.options);
-                let result3 = self.push(&inst.arg1, (data1 + data2) as u8);
+                let value = self.alu.add(data1, data2);
+                let result3 = self.push(&inst.arg1, value);
                 result1 | result2 << 1 | result3 << 2
             }
             Sub => {
                 let ((data1, result1), (data2, result2)) =
                     self.load_double_signed(&inst.arg1, &inst.arg2, &inst.options);
-                let result3 = self.push(&inst.arg1, (data1 - data2) as u8);
+                let value = self.alu.sub(data1, data2);
+                let result3 = self.push(&inst.arg1, value);
                 result1 | result2 << 1 | result3 << 2
             }
             Mul => {
                 let ((data1, result1), (data2, result2)) =
                     self.load_double_signed(&inst.arg1, &inst.arg2, &inst.options);
-                let result3 = self.push(&inst.arg1, (data1 * data2) as u8);
+                let value = self.alu.mul(data1, data2);
+                let result3 = self.push(&inst.arg1, value);
                 result1 | result2 << 1 | result3 << 2
             }
             Div => {
                 let ((data1, result1), (data2, result2)) =
                     self.load_double_signed(&inst.arg1, &inst.arg2, &inst.options);
-                let result3 = self.push(&inst.arg1, (data1 / data2) as u8);
-                result1 | result2 << 1 | result3 << 2
+                match self.alu.div(data1, data2) {
+                    Some(quotient) => {
+                        let result3 = self.push(&inst.arg1, quotient);
+                        result1 | result2 << 1 | result3 << 2
+                    }
+                    None => result1 | result2 << 1 | 1 << 3,
+                }
             }
             SL => {
                 let (data1, result1) = self.load(&inst.arg1);
@@ -111,33 +860,76 @@ impl CPU {
             CompEq => {
                 let ((data1, result1), (data2, result2)) = self.load_double_signed(&inst.arg1, &inst.arg2, &inst.options);
                 if data1 != data2 {
-                    self.reg_zero += 3;
+                    self.reg_zero = self.reg_zero.wrapping_add(3);
                 }
                 result1 | result2 << 1
             }
             CompGt => {
                 let ((data1, result1), (data2, result2)) = self.load_double_signed(&inst.arg1, &inst.arg2, &inst.options);
                 if data1 <= data2 {
-                    self.reg_zero += 3;
+                    self.reg_zero = self.reg_zero.wrapping_add(3);
                 }
                 result1 | result2 << 1
             }
             CompLt => {
                 let ((data1, result1), (data2, result2)) = self.load_double_signed(&inst.arg1, &inst.arg2, &inst.options);
                 if data1 >= data2 {
-                    self.reg_zero += 3;
+                    self.reg_zero = self.reg_zero.wrapping_add(3);
                 }
                 result1 | result2 << 1
             }
         };
-        if inst.options.halt_on_error() && result != 0 {
+        // A rejected jump halts right away, leaving the PC on the jump and
+        // bypassing trap vectors, the fault handler and halt-on-error
+        if self.illegal_jump.is_some() {
             return Halted::Halted;
         }
+        if result != 0 {
+            if let Some(vector) = self.trap_vectors.vector(result) {
+                if self.trap_pc_overflow && !self.pc_in_range(vector) {
+                    self.illegal_jump = Some(vector);
+                    return Halted::Halted;
+                }
+                self.reg_zero = vector;
+                return Halted::Running;
+            }
+            if self.handle_fault(inst, result) == Halted::Halted {
+                return Halted::Halted;
+            }
+        }
 
-        self.reg_zero += 3;
+        self.reg_zero = self.reg_zero.wrapping_add(3);
         Halted::Running
     }
 
+    fn handle_fault(&mut self, instruction: Instruction, errors: u8) -> Halted {
+        let halt_on_error = instruction.options.halt_on_error();
+        let action = match self.fault_handler.take() {
+            Some(mut handler) => {
+                let action = handler(
+                    &Fault {
+                        instruction,
+                        errors,
+                    },
+                    self,
+                );
+                // The handler may have installed a replacement for itself
+                self.fault_handler.get_or_insert(handler);
+                // or edited memory directly
+                if self.memory_hash.is_some() {
+                    self.memory_hash = Some(self.full_memory_hash());
+                }
+                action
+            }
+            None => FaultAction::Propagate,
+        };
+        match action {
+            FaultAction::Propagate if halt_on_error => Halted::Halted,
+            FaultAction::Propagate | FaultAction::Resume => Halted::Running,
+            FaultAction::Halt => Halted::Halted,
+        }
+    }
+
     fn load_double(&mut self, addr1: &Symbol, addr2: &Symbol) -> ((u8, u8), (u8, u8)) {
         (self.load(addr1), self.load(addr2))
     }
@@ -162,26 +954,872 @@ impl CPU {
     }
 
     fn load(&mut self, addr: &Symbol) -> (u8, u8) {
-        match addr.address() {
+        let address = self.aliases.translate(addr.address());
+        if !self.check_access(address, AccessKind::Read) {
+            return (0, 1);
+        }
+        if let Some(region) = self.profile_region(address) {
+            if let Some(profiler) = &mut self.profiler {
+                profiler.record_load(region);
+            }
+        }
+        let (data, result) = self.load_bus(address);
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push(Access::new(address, false, data));
+        }
+        (data, result)
+    }
+
+    fn load_bus(&mut self, address: u8) -> (u8, u8) {
+        match address {
             0 => (self.reg_zero, 0),
-            1..=127 => (self.inst_mem[addr.address() as usize], 0),
-            128..=191 => (self.data_mem[(addr.address() - 128) as usize], 0),
+            // Note that jumps usually load their target from an instruction argument
+            1..=127 if !self.data_reads_from_code => (0, 1),
+            1..=126 => (self.inst_mem[address as usize], 0),
+            // The instruction bank only holds 127 bytes, indexed from 0
+            127 => (0, 1),
+            128..=191 => {
+                let index = (address - 128) as usize;
+                match &self.data_written {
+                    Some(written) if !written[self.data_mem.pointer as usize][index] => (0, 1),
+                    _ => (self.data_mem[index], 0),
+                }
+            }
             192 => (self.inst_mem.pointer, 0),
             193 => (self.data_mem.pointer, 0),
-            _ => (0, 1),
+            _ => match self.device(address).map(|device| device.load(address)) {
+                Some(Ok(data)) => (data, 0),
+                Some(Err(error)) => {
+                    self.device_error = Some(error);
+                    (0, 1)
+                }
+                None => (0, 1),
+            },
         }
     }
 
     fn push(&mut self, addr: &Symbol, data: u8) -> u8 {
-        println!("From push(): addr:{}, data:{}", addr.address(), data);
-        match addr.address() {
+        let address = self.aliases.translate(addr.address());
+        if !self.check_access(address, AccessKind::Write) {
+            return 1;
+        }
+        if let Some(region) = self.profile_region(address) {
+            if let Some(profiler) = &mut self.profiler {
+                profiler.record_push(region);
+            }
+        }
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push(Access::new(address, true, data));
+        }
+        self.journal_write(address);
+        if self.log_data_write(address, data).is_err() {
+            return 1;
+        }
+        self.push_bus(address, data)
+    }
+
+    fn push_bus(&mut self, address: u8, data: u8) -> u8 {
+        match address {
+            // The PC is advanced by 3 after the store, so check where the next fetch lands
+            0 if self.trap_pc_overflow && !self.pc_in_range(data.wrapping_add(3)) => {
+                self.illegal_jump = Some(data);
+                return 1;
+            }
             0 => self.reg_zero = data,
-            1..=127 => self.inst_mem[addr.address() as usize] = data,
-            128..=191 => self.data_mem[(addr.address() - 128) as usize] = data,
+            1..=126 => {
+                let old = self.inst_mem[address as usize];
+                self.rehash(0, self.inst_mem.pointer, address, old, data);
+                self.inst_mem[address as usize] = data;
+                self.invalidate_decode(address);
+            }
+            127 => return 1,
+            128..=191 => {
+                let index = (address - 128) as usize;
+                if let Some(written) = &mut self.data_written {
+                    written[self.data_mem.pointer as usize][index] = true;
+                }
+                let old = self.data_mem[index];
+                self.rehash(1, self.data_mem.pointer, index as u8, old, data);
+                self.data_mem[index] = data;
+            }
             192 => self.inst_mem.pointer = data,
             193 => self.data_mem.pointer = data,
-            _ => return 1,
+            _ => match self
+                .device(address)
+                .map(|device| device.push(address, data))
+            {
+                Some(Ok(())) => {}
+                Some(Err(error)) => {
+                    self.device_error = Some(error);
+                    return 1;
+                }
+                None => return 1,
+            },
         }
         0
     }
+
+    // Region the profiler counts an access under, None while it is off.
+    // Device accesses count towards the device answering them, keyed by its
+    // base address, unmapped device addresses count on their own.
+    fn profile_region(&mut self, address: u8) -> Option<Region> {
+        self.profiler.as_ref()?;
+        Some(match Region::of(address) {
+            Region::Device(_) => Region::Device(
+                self.devices
+                    .find(address)
+                    .map_or(address, |device| device.address()),
+            ),
+            region => region,
+        })
+    }
+
+    // Whether a complete instruction starts at `pc`
+    fn pc_in_range(&self, pc: u8) -> bool {
+        self.peek(pc).is_some()
+    }
+
+    fn device(&mut self, addr: u8) -> Option<&mut Box<dyn Device>> {
+        let device = self.devices.find(addr)?;
+        self.cycles += device.latency();
+        Some(device)
+    }
+}
+
+// One cell of a memory hash, regions are 0 for instructions and 1 for data.
+// livelock_hash uses 2 for the registers.
+fn cell_hash(region: u8, bank: u8, index: u8, value: u8) -> u64 {
+    mix(u32::from_be_bytes([region, bank, index, value]) as u64)
+}
+
+// SplitMix64 finalizer
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, devices::RomDevice};
+
+    fn load_code(code: &[u8]) -> CPU {
+        let mut inst_mem = [0; 127];
+        inst_mem[..code.len()].copy_from_slice(code);
+        CPU::new(inst_mem, Vec::new())
+    }
+
+    fn load(source: &str) -> CPU {
+        let code = assembler::assemble(source).unwrap();
+        CPU {
+            program_len: Some(code.len()),
+            ..load_code(&code)
+        }
+    }
+
+    #[test]
+    fn registers_by_enum() {
+        let mut cpu = load_code(&[]);
+        cpu.write_reg(Reg::Zero, 9);
+        cpu.write_reg(Reg::InstPage, 2);
+        cpu.write_reg(Reg::DataPage, 3);
+        assert_eq!(cpu.reg_zero, 9);
+        assert_eq!(cpu.inst_mem.pointer, 2);
+        assert_eq!(cpu.data_mem.pointer, 3);
+        assert_eq!(cpu.read_reg(Reg::DataPage), 3);
+        assert_eq!(Reg::InstPage.address(), 192);
+    }
+
+    #[test]
+    fn reg_addresses_match_the_bus() {
+        // COPY #C1 #80, NOOP #00 #00
+        let mut cpu = load_code(&[0x0C, 0xC1, 0x80, 0x00, 0x00, 0x00]);
+        cpu.write_reg(Reg::DataPage, 4);
+        cpu.tick();
+        assert_eq!(cpu.data_mem[0], cpu.read_reg(Reg::DataPage));
+    }
+
+    #[test]
+    fn from_reader_loads_a_program_with_header() {
+        let image = program::with_header(&[0x0C, 0x01, 0x80]).unwrap();
+        let cpu = CPU::from_reader(&image[..], Vec::new()).unwrap();
+        assert_eq!(cpu.program_len, Some(3));
+        assert_eq!(&cpu.inst_mem[0..4], &[0x0C, 0x01, 0x80, 0]);
+
+        let error = CPU::from_reader(&b"nope\x01\x00"[..], Vec::new())
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = CPU::from_reader(&image[..5], Vec::new()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn poisoned_data_reads_as_zero_until_written() {
+        // COPY #80 #81, NOOP #00 #00
+        let mut cpu = load_code(&[0x0C, 0x80, 0x81, 0x00, 0x00, 0x00]);
+        cpu.poison_data_mem();
+        cpu.data_mem[0] = 5;
+        cpu.tick();
+        assert_eq!(cpu.data_mem[1], 0);
+
+        // COPY #01 #80, COPY #80 #81, NOOP #00 #00
+        let mut cpu = load_code(&[0x0C, 0x01, 0x80, 0x0C, 0x80, 0x81, 0x00, 0x00, 0x00]);
+        cpu.poison_data_mem();
+        cpu.tick();
+        cpu.tick();
+        assert_eq!(cpu.data_mem[1], 0x01);
+    }
+
+    #[test]
+    fn removing_devices_unmaps_them() {
+        let devices: Vec<Box<dyn Device>> = vec![
+            Box::new(RomDevice::new(200, vec![1]).unwrap()),
+            Box::new(RomDevice::new(210, vec![2]).unwrap()),
+        ];
+        // COPY #C8 #80, COPY #D2 #81
+        let code = [0x0C, 0xC8, 0x80, 0x0C, 0xD2, 0x81];
+        let mut cpu = load_code(&code);
+        cpu.devices = DeviceMap::new(devices).unwrap();
+        assert!(cpu.remove_device(5).is_none());
+        assert_eq!(cpu.remove_device(0).unwrap().address(), 200);
+        cpu.tick();
+        cpu.tick();
+        assert_eq!((cpu.data_mem[0], cpu.data_mem[1]), (0, 2));
+
+        assert_eq!(cpu.clear_devices().len(), 1);
+        cpu.reg_zero = 0;
+        cpu.tick();
+        cpu.tick();
+        assert_eq!(cpu.data_mem[1], 0);
+    }
+
+    #[test]
+    fn access_log_records_loads_and_stores_in_order() {
+        // COPY #01 #80, NOOP #00 #00
+        let mut cpu = load_code(&[0x0C, 0x01, 0x80, 0x00, 0x00, 0x00]);
+        cpu.tick();
+        assert!(cpu.access_log().is_empty());
+
+        cpu.reg_zero = 0;
+        cpu.enable_access_log();
+        cpu.tick();
+        assert_eq!(
+            cpu.access_log(),
+            &[Access::new(1, false, 0x01), Access::new(0x80, true, 0x01)]
+        );
+    }
+
+    #[test]
+    fn peek_decodes_without_executing() {
+        // NOOP #00 #00, COPY #01 #80
+        let cpu = load_code(&[0x00, 0x00, 0x00, 0x0C, 0x01, 0x80]);
+        let inst = cpu.peek(3).unwrap();
+        assert!(matches!(inst.code, crate::OpCode::Copy));
+        assert_eq!((inst.arg1.address(), inst.arg2.address()), (0x01, 0x80));
+        assert_eq!(cpu.reg_zero, 0);
+
+        assert!(cpu.peek(124).is_some());
+        assert!(cpu.peek(125).is_none());
+        assert!(cpu.peek(255).is_none());
+    }
+
+    #[test]
+    fn out_of_range_jump_halts_eagerly() {
+        // NOOP #C5 #00, COPY #01 #00, NOOP #00 #00
+        let mut cpu = load_code(&[0x00, 0xC5, 0x00, 0x0C, 0x01, 0x00, 0x00, 0x00, 0x00]);
+        cpu.trap_pc_overflow = true;
+        cpu.tick();
+        assert_eq!(cpu.tick(), Halted::Halted);
+        assert_eq!(cpu.illegal_jump, Some(0xC5));
+        assert_eq!(cpu.reg_zero, 3);
+    }
+
+    #[test]
+    fn out_of_range_pc_writes_and_trap_vectors_are_rejected() {
+        let mut cpu = load("DIV #80 #81\nNOOP #00 #00");
+        cpu.trap_pc_overflow = true;
+        cpu.write_reg(Reg::Zero, 0xC5);
+        assert_eq!((cpu.reg_zero, cpu.illegal_jump), (0, Some(0xC5)));
+        cpu.write_reg(Reg::Zero, 3);
+        assert_eq!(cpu.reg_zero, 3);
+
+        cpu.reg_zero = 0;
+        cpu.trap_vectors.divide_by_zero = Some(125);
+        assert_eq!(cpu.tick(), Halted::Halted);
+        assert_eq!((cpu.reg_zero, cpu.illegal_jump), (0, Some(125)));
+    }
+
+    #[test]
+    fn in_range_jump_is_allowed() {
+        // NOOP #03 #00, COPY #01 #00, NOOP #00 #00
+        let mut cpu = load_code(&[0x00, 0x03, 0x00, 0x0C, 0x01, 0x00, 0x00, 0x00, 0x00]);
+        cpu.trap_pc_overflow = true;
+        cpu.tick();
+        assert_eq!(cpu.tick(), Halted::Running);
+        assert_eq!(cpu.reg_zero, 6);
+        assert_eq!(cpu.illegal_jump, None);
+    }
+
+    struct Input(Vec<u8>);
+
+    impl Device for Input {
+        fn load(&mut self, addr: u8) -> Result<u8, DeviceError> {
+            if self.0.is_empty() {
+                return Err(DeviceError::Failed(addr));
+            }
+            Ok(self.0.remove(0))
+        }
+
+        fn push(&mut self, addr: u8, _data: u8) -> Result<(), DeviceError> {
+            Err(DeviceError::ReadOnly(addr))
+        }
+
+        fn address(&self) -> u8 {
+            200
+        }
+    }
+
+    #[test]
+    fn registered_devices_are_reachable_by_index() {
+        // COPY #C8 #80, COPY #C8 #81
+        let mut cpu = load_code(&[0x0C, 0xC8, 0x80, 0x0C, 0xC8, 0x81]);
+        let devices: Vec<Box<dyn Device>> = vec![
+            Box::new(Input(Vec::new())),
+            Box::new(RomDevice::new(210, vec![1]).unwrap()),
+        ];
+        cpu.devices = DeviceMap::new(devices).unwrap();
+        assert_eq!(cpu.device_mut(1).unwrap().address(), 210);
+        assert!(cpu.device_mut(2).is_none());
+        assert!(cpu.device_downcast_mut::<RomDevice>(0).is_none());
+        cpu.device_downcast_mut::<Input>(0)
+            .unwrap()
+            .0
+            .extend([4, 5]);
+        cpu.tick();
+        cpu.tick();
+        assert_eq!((cpu.data_mem[0], cpu.data_mem[1]), (4, 5));
+    }
+
+    #[test]
+    fn tight_loop_is_a_livelock() {
+        // NOOP #00 #00, NOOP #00 #00, COPY #02 #00
+        let mut cpu = load_code(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x02, 0x00]);
+        cpu.detect_livelock = true;
+        assert_eq!(cpu.run(100), RunOutcome::Livelock { length: 2 });
+    }
+
+    #[test]
+    fn loop_toggling_memory_is_a_livelock() {
+        // NOOP #00 #00, NOT #80 #00, COPY #05 #00
+        let mut cpu = load_code(&[0x00, 0x00, 0x00, 0x03, 0x80, 0x00, 0x0C, 0x05, 0x00]);
+        cpu.detect_livelock = true;
+        // The data byte flips every pass, so the state repeats every 2 passes
+        assert_eq!(cpu.run(100), RunOutcome::Livelock { length: 4 });
+    }
+
+    #[test]
+    fn counting_loop_is_not_a_livelock() {
+        // NOOP #00 #01, ADD #80 #02, COPY #01 #00
+        let mut cpu = load_code(&[0x00, 0x00, 0x01, 0x04, 0x80, 0x02, 0x0C, 0x01, 0x00]);
+        cpu.detect_livelock = true;
+        assert_eq!(cpu.run(200), RunOutcome::OutOfFuel);
+        cpu.detect_livelock = false;
+        assert_eq!(cpu.run(200), RunOutcome::OutOfFuel);
+    }
+
+    #[test]
+    fn memory_hash_follows_stores() {
+        // NOOP #00 #01, ADD #80 #02, COPY #80 #7E, COPY #01 #00
+        let mut cpu = load_code(&[
+            0x00, 0x00, 0x01, 0x04, 0x80, 0x02, 0x0C, 0x80, 0x7E, 0x0C, 0x01, 0x00,
+        ]);
+        cpu.memory_hash = Some(cpu.full_memory_hash());
+        for _ in 0..20 {
+            cpu.tick();
+        }
+        assert_eq!(cpu.memory_hash, Some(cpu.full_memory_hash()));
+    }
+
+    #[test]
+    fn data_loads_from_code_can_be_forbidden() {
+        // NOOP #2A #00, COPY #01 #80, NOOP #00 #00
+        let code = [0x00, 0x2A, 0x00, 0x0C, 0x01, 0x80, 0x00, 0x00, 0x00];
+        let mut cpu = load_code(&code);
+        cpu.run(2);
+        assert_eq!(cpu.data_mem[0], 0x2A);
+
+        let mut cpu = load_code(&code);
+        cpu.data_reads_from_code = false;
+        cpu.run(2);
+        assert_eq!(cpu.data_mem[0], 0);
+    }
+
+    #[test]
+    fn end_of_program_policies() {
+        // NOOP #7B #00, COPY #01 #00
+        let code = [0x00, 0x7B, 0x00, 0x0C, 0x01, 0x00];
+        let mut cpu = load_code(&code);
+        cpu.end_of_program = EndOfProgram::Error;
+        assert_eq!(cpu.run(10), RunOutcome::Halted);
+        assert_eq!(cpu.overrun, Some(126));
+
+        let mut cpu = load_code(&code);
+        assert_eq!(cpu.run(10), RunOutcome::Halted);
+        assert_eq!(cpu.reg_zero, 126);
+        assert_eq!(cpu.overrun, None);
+
+        let mut cpu = load_code(&code);
+        cpu.end_of_program = EndOfProgram::Wrap;
+        assert_eq!(cpu.run(3), RunOutcome::OutOfFuel);
+        assert_eq!(cpu.reg_zero, 3);
+        assert_eq!(cpu.overrun, None);
+    }
+
+    #[test]
+    fn data_memory_dump_round_trip() {
+        let mut cpu = load_code(&[]);
+        cpu.data_mem.content[3][5] = 9;
+        let bytes = cpu.data_mem_bytes();
+        assert_eq!(bytes.len(), 256 * 64);
+        assert_eq!(bytes[3 * 64 + 5], 9);
+
+        let mut other = load_code(&[]);
+        other.set_data_mem_bytes(&bytes).unwrap();
+        assert_eq!(other.data_mem.content[3][5], 9);
+        assert_eq!(
+            other.set_data_mem_bytes(&bytes[1..]),
+            Err(LengthMismatch {
+                expected: 256 * 64,
+                found: 256 * 64 - 1
+            })
+        );
+    }
+
+    #[test]
+    fn fault_handler_decides_whether_to_halt() {
+        let source = "COPY #01 #80\nCOPY :1000 #01 #80\nCOPY #00 #82";
+        let run = |action: FaultAction| {
+            let mut cpu = load(source);
+            // makes both loads fail
+            cpu.data_reads_from_code = false;
+            let faults = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let seen = faults.clone();
+            cpu.set_fault_handler(move |fault, _| {
+                seen.lock().unwrap().push(fault.errors);
+                action
+            });
+            cpu.run(10);
+            let faults = faults.lock().unwrap().clone();
+            (cpu.data_mem[2], faults)
+        };
+        assert_eq!(run(FaultAction::Propagate), (0, vec![0b001, 0b001]));
+        assert_eq!(run(FaultAction::Resume), (6, vec![0b001, 0b001]));
+        assert_eq!(run(FaultAction::Halt), (0, vec![0b001]));
+    }
+
+    // Answers 0 at address 200 after `latency` extra cycles
+    struct Slow(u64);
+
+    impl Device for Slow {
+        fn load(&mut self, _addr: u8) -> Result<u8, DeviceError> {
+            Ok(0)
+        }
+
+        fn push(&mut self, _addr: u8, _data: u8) -> Result<(), DeviceError> {
+            Ok(())
+        }
+
+        fn address(&self) -> u8 {
+            200
+        }
+
+        fn latency(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn device_latency_adds_cycles() {
+        let mut cpu = load("COPY #C8 #C8\nNOOP #00 #00");
+        cpu.devices.push(Box::new(Slow(5))).unwrap();
+        // looking up the device to profile does not count as an access
+        cpu.enable_profiling();
+        cpu.tick();
+        assert_eq!(cpu.cycles, 11);
+        cpu.tick();
+        assert_eq!(cpu.cycles, 12);
+    }
+
+    #[test]
+    fn default_cpu_runs_empty_memory_to_the_end() {
+        let mut cpu = CPU::default();
+        assert_eq!(cpu.run(100), RunOutcome::Halted);
+        assert_eq!(cpu.cycles, 42);
+        assert_eq!(cpu.reg_zero, 126);
+        assert!(cpu.devices().is_empty());
+    }
+
+    // xorshift64, so the arbitrary programs are the same on every run
+    fn random_bytes(seed: &mut u64, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 7;
+                *seed ^= *seed << 17;
+                *seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn arbitrary_programs_do_not_panic() {
+        let mut seed = 0x2545_F491_4F6C_DD1D;
+        for round in 0..300 {
+            let bytes = random_bytes(&mut seed, 130);
+            let mut inst_mem = [0; 127];
+            inst_mem.copy_from_slice(&bytes[..127]);
+            let devices: Vec<Box<dyn Device>> =
+                vec![Box::new(RomDevice::new(250, vec![1, 2]).unwrap())];
+            let mut cpu = CPU::new(inst_mem, devices);
+            cpu.write_reg(Reg::Zero, bytes[127]);
+            cpu.write_reg(Reg::InstPage, bytes[128]);
+            cpu.write_reg(Reg::DataPage, bytes[129]);
+            cpu.trap_pc_overflow = round % 2 == 0;
+            cpu.strict = round % 3 == 0;
+            cpu.trap_misaligned = round % 5 == 0;
+            cpu.detect_livelock = round % 7 == 0;
+            cpu.data_reads_from_code = round % 11 != 0;
+            if round % 4 == 0 {
+                cpu.end_of_program = EndOfProgram::Wrap;
+                cpu.enable_decode_cache();
+                cpu.poison_data_mem();
+            } else {
+                cpu.enable_journal();
+                cpu.enable_profiling();
+                cpu.enable_coverage();
+                cpu.enable_trace();
+            }
+            cpu.run(500);
+            while cpu.step_back() {}
+        }
+    }
+
+    #[test]
+    fn trap_vectors_redirect_faults() {
+        let source = "DIV #80 #81\nCOPY #80 #7F\nCOPY #D0 #80\nNOOP #00 #00";
+        let mut cpu = load(source);
+        cpu.trap_vectors = TrapVectors {
+            illegal_load: Some(30),
+            illegal_store: Some(20),
+            divide_by_zero: Some(3),
+        };
+        cpu.tick();
+        assert_eq!(cpu.reg_zero, 3);
+        cpu.tick();
+        assert_eq!(cpu.reg_zero, 20);
+        cpu.reg_zero = 6;
+        cpu.tick();
+        assert_eq!(cpu.reg_zero, 30);
+
+        // Faults without a vector continue as before
+        let mut cpu = load(source);
+        cpu.trap_vectors.illegal_load = Some(30);
+        cpu.run(2);
+        assert_eq!(cpu.reg_zero, 6);
+    }
+
+    #[test]
+    fn strict_mode_halts_in_padding() {
+        let mut cpu = load("COPY #01 #80\nNOOP #00 #00");
+        cpu.strict = true;
+        assert_eq!(cpu.run(100), RunOutcome::Halted);
+        assert_eq!((cpu.reg_zero, cpu.cycles), (6, 2));
+        assert_eq!(cpu.padding_pc, Some(6));
+
+        // Without a known program length every byte counts as program
+        let mut cpu = load("COPY #01 #80\nNOOP #00 #00");
+        cpu.strict = true;
+        cpu.program_len = None;
+        cpu.run(100);
+        assert_eq!(cpu.cycles, 42);
+        assert_eq!(cpu.padding_pc, None);
+    }
+
+    #[test]
+    fn state_hash_covers_registers_and_memory_but_not_cycles() {
+        let cpu = CPU::default();
+        let hash = cpu.state_hash();
+        assert_eq!(CPU::default().state_hash(), hash);
+
+        let mut other = CPU {
+            cycles: 10,
+            ..CPU::default()
+        };
+        assert_eq!(other.state_hash(), hash);
+        other.data_mem.content[255][63] = 1;
+        assert_ne!(other.state_hash(), hash);
+
+        let mut other = CPU::default();
+        other.write_reg(Reg::InstPage, 1);
+        assert_ne!(other.state_hash(), hash);
+    }
+
+    #[test]
+    fn misaligned_pc_halts_when_trapped() {
+        let source = "NOOP #01 #00\nCOPY #01 #00\nNOOP #00 #00";
+        let mut cpu = load(source);
+        cpu.trap_misaligned = true;
+        assert_eq!(cpu.run(10), RunOutcome::Halted);
+        assert_eq!((cpu.reg_zero, cpu.cycles), (4, 2));
+        assert_eq!(cpu.misaligned_pc, Some(4));
+
+        let mut cpu = load(source);
+        cpu.run(3);
+        assert_eq!((cpu.reg_zero, cpu.cycles), (7, 3));
+        assert_eq!(cpu.misaligned_pc, None);
+    }
+
+    #[test]
+    fn with_image_loads_code_and_initial_data() {
+        let cpu = CPU::with_image(&[0x0C, 0x80, 0x81], &[7, 8], Vec::new()).unwrap();
+        assert_eq!(cpu.program_len, Some(3));
+        assert_eq!(cpu.inst_mem.content[200][..3], [0x0C, 0x80, 0x81]);
+        assert_eq!(cpu.data_mem.content[0][..3], [7, 8, 0]);
+        assert_eq!(cpu.data_mem.content[1][0], 0);
+
+        let error = CPU::with_image(&[0; 128], &[], Vec::new()).err().unwrap();
+        assert_eq!(
+            error,
+            ImageError::Length(LengthMismatch {
+                expected: 127,
+                found: 128
+            })
+        );
+        let error = CPU::with_image(&[], &[0; 65], Vec::new()).err().unwrap();
+        assert_eq!(
+            error,
+            ImageError::Length(LengthMismatch {
+                expected: 64,
+                found: 65
+            })
+        );
+    }
+
+    #[test]
+    fn constructors_reject_overlapping_devices() {
+        let overlapping = || -> Vec<Box<dyn Device>> {
+            vec![
+                Box::new(RomDevice::new(200, vec![0; 4]).unwrap()),
+                Box::new(RomDevice::new(202, vec![0]).unwrap()),
+            ]
+        };
+        let overlap = DeviceOverlap { address: 202 };
+        assert_eq!(CPU::try_new([0; 127], overlapping()).err(), Some(overlap));
+        assert_eq!(
+            CPU::with_image(&[], &[], overlapping()).err(),
+            Some(ImageError::Overlap(overlap))
+        );
+        assert_eq!(
+            CPU::load_relocatable(&[], 0, &[], overlapping()).err(),
+            Some(RelocateError::Overlap(overlap))
+        );
+        let image = program::with_header(&[]).unwrap();
+        let error = CPU::from_reader(&image[..], overlapping()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn reg_watch_stops_run() {
+        let mut cpu = load("NOOP $top #01\n$top\nADD #C1 #02\nCOPY #01 #00");
+        cpu.watch_reg(Reg::DataPage, 3);
+        assert_eq!(
+            cpu.run(100),
+            RunOutcome::Watch {
+                reg: Reg::DataPage,
+                value: 3
+            }
+        );
+        assert_eq!(cpu.read_reg(Reg::DataPage), 3);
+        assert_eq!(cpu.reg_zero, 6);
+    }
+
+    #[test]
+    fn free_space_and_trim() {
+        let mut inst_mem = [0; 127];
+        inst_mem[7] = 0x80;
+        let mut cpu = CPU::new(inst_mem, Vec::new());
+        assert_eq!(cpu.program_len, None);
+        assert_eq!(cpu.free_space(), 118);
+
+        // a later instruction in another bank counts too
+        cpu.inst_mem.content[4][12] = 0x0C;
+        cpu.program_len = Some(30);
+        assert_eq!(cpu.free_space(), 97);
+        cpu.trim();
+        assert_eq!(cpu.program_len, Some(15));
+        assert_eq!(CPU::default().free_space(), 127);
+
+        cpu.program_len = Some(200);
+        assert_eq!(cpu.free_space(), 0);
+    }
+
+    #[test]
+    fn added_devices_keep_their_index() {
+        let mut cpu = CPU::new(
+            [0; 127],
+            vec![Box::new(RomDevice::new(240, vec![1]).unwrap())],
+        );
+        cpu.add_device(Box::new(RomDevice::new(200, vec![2]).unwrap()))
+            .unwrap();
+        assert_eq!(cpu.device_mut(0).unwrap().address(), 240);
+        assert_eq!(cpu.device_mut(1).unwrap().address(), 200);
+        assert_eq!(cpu.load_bus(200), (2, 0));
+
+        let overlapping = RomDevice::new(240, vec![3]).unwrap();
+        assert_eq!(
+            cpu.add_device(Box::new(overlapping)),
+            Err(AddDeviceError::Overlap(DeviceOverlap { address: 240 }))
+        );
+        assert_eq!(cpu.devices().len(), 2);
+    }
+
+    #[test]
+    fn reset_registers_keeps_memory() {
+        let mut cpu = load("NOOP #02 #03\nCOPY #01 #C1\nCOPY #02 #80");
+        cpu.run(3);
+        assert_eq!(cpu.read_reg(Reg::DataPage), 2);
+        cpu.reset_registers();
+        for reg in [Reg::Zero, Reg::InstPage, Reg::DataPage] {
+            assert_eq!(cpu.read_reg(reg), 0);
+        }
+        assert_eq!(cpu.cycles, 3);
+        assert_eq!(cpu.data_mem.content[2][0], 3);
+    }
+
+    #[test]
+    fn device_cap_limits_add_device() {
+        let mut cpu = CPU::default();
+        cpu.set_max_devices(1);
+        cpu.add_device(Box::new(RomDevice::new(200, vec![1]).unwrap()))
+            .unwrap();
+        assert_eq!(
+            cpu.add_device(Box::new(RomDevice::new(210, vec![2]).unwrap())),
+            Err(AddDeviceError::TooMany { max: 1 })
+        );
+        assert_eq!(cpu.devices().len(), 1);
+        cpu.remove_device(0);
+        assert!(cpu
+            .add_device(Box::new(RomDevice::new(210, vec![2]).unwrap()))
+            .is_ok());
+    }
+
+    #[test]
+    fn relocated_programs_run_the_same_at_any_base() {
+        let source = include_str!("../fixtures/sum_loop.ysm");
+        let (program, info) = assembler::assemble_with_debug(source).unwrap();
+        for base in [0, 30, 105] {
+            let mut cpu =
+                CPU::load_relocatable(&program, base, &info.relocations, Vec::new()).unwrap();
+            assert_eq!(cpu.run(1000), RunOutcome::Halted);
+            assert_eq!(cpu.data_mem[0], 15, "base {base}");
+        }
+    }
+
+    #[test]
+    fn load_relocatable_rejects_bad_input() {
+        let program = [0x0C, 0x01, 0x80];
+        assert_eq!(
+            CPU::load_relocatable(&program, 4, &[], Vec::new()).err(),
+            Some(RelocateError::MisalignedBase(4))
+        );
+        assert_eq!(
+            CPU::load_relocatable(&program, 0, &[3], Vec::new()).err(),
+            Some(RelocateError::Offset(3))
+        );
+        assert!(matches!(
+            CPU::load_relocatable(&program, 126, &[], Vec::new()),
+            Err(RelocateError::Length(_))
+        ));
+    }
+
+    #[test]
+    fn tick_guarded_returns_panics_as_errors() {
+        let query =
+            crate::devices::HostQuery::new(200, 1, |selector| panic!("query {selector} failed"))
+                .unwrap();
+        let code = assembler::assemble("NOOP #00 #00\nCOPY #C8 #80").unwrap();
+        let mut cpu = CPU::with_image(&code, &[], vec![Box::new(query)]).unwrap();
+        assert_eq!(cpu.tick_guarded(), Ok(Halted::Running));
+        assert_eq!(
+            cpu.tick_guarded(),
+            Err(InternalPanic("query 0 failed".to_owned()))
+        );
+    }
+
+    #[test]
+    fn pause_flag_stops_run_before_the_next_tick() {
+        let mut cpu = load("NOOP #00 #00\nCOPY #02 #00");
+        let pause = cpu.pause_handle();
+        pause.store(true, Ordering::Relaxed);
+        assert_eq!(cpu.run(100), RunOutcome::Paused);
+        assert_eq!(cpu.cycles, 0);
+
+        // A device loaded at 03 sets the flag while the program runs
+        let code =
+            assembler::assemble("NOOP #00 #00\nCOPY #C8 #80\nNOOP #00 #00\nCOPY #0A #00").unwrap();
+        let flag = Arc::new(AtomicBool::new(false));
+        let setter = flag.clone();
+        let query = crate::devices::HostQuery::new(200, 1, move |_| {
+            setter.store(true, Ordering::Relaxed);
+            7
+        })
+        .unwrap();
+        let mut cpu = CPU::with_image(&code, &[], vec![Box::new(query)]).unwrap();
+        cpu.pause = Some(flag);
+        assert_eq!(cpu.run(100), RunOutcome::Paused);
+        assert_eq!((cpu.reg_zero, cpu.cycles), (6, 2));
+        assert_eq!(cpu.data_mem[0], 7);
+    }
+
+    #[test]
+    fn run_to_stops_at_an_address() {
+        let mut cpu = load(include_str!("../fixtures/sum_loop.ysm"));
+        cpu.watch_reg(Reg::DataPage, 9);
+        assert_eq!(
+            cpu.run_to(18, 100),
+            RunOutcome::Watch {
+                reg: Reg::Zero,
+                value: 18
+            }
+        );
+        // sum of 5, counter already down to 4
+        assert_eq!(cpu.data_mem[0..2], [5, 4]);
+        assert_eq!(cpu.reg_watches, [(Reg::DataPage, 9)]);
+        assert_eq!(cpu.run_to(18, 2), RunOutcome::OutOfFuel);
+    }
+
+    #[test]
+    fn decode_cache_sees_stores_to_instructions() {
+        // the second instruction rewrites the source operand of the first
+        let source = "COPY #01 #80\nCOPY #08 #01\nNOOP #00 #81";
+        let mut outputs = Vec::new();
+        for cached in [false, true] {
+            let mut cpu = load(source);
+            if cached {
+                cpu.enable_decode_cache();
+            }
+            cpu.tick();
+            cpu.tick();
+            cpu.reg_zero = 0;
+            cpu.tick();
+            outputs.push(cpu.data_mem[0]);
+            if cached {
+                assert_eq!(cpu.decode_cache.as_ref().unwrap().len(), 2);
+            }
+        }
+        assert_eq!(outputs, [0, 0]);
+    }
 }
@@ -0,0 +1,202 @@
+use std::io::{self, Read, Write};
+
+use crate::emulation::CPU;
+
+// Previous contents of a cell overwritten during a step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Undo {
+    Inst { bank: u8, index: u8, value: u8 },
+    Data { bank: u8, index: u8, value: u8 },
+    InstPage(u8),
+    DataPage(u8),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JournalStep {
+    pub pc: u8,
+    pub cycles: u64,
+    pub undo: Vec<Undo>,
+}
+
+impl CPU {
+    // Writes to devices cannot be reverted and are not journaled
+    pub fn enable_journal(&mut self) {
+        self.journal.get_or_insert_with(Vec::new);
+    }
+
+    // Reverts the most recent tick, returns false once the journal is empty
+    pub fn step_back(&mut self) -> bool {
+        let Some(step) = self.journal.as_mut().and_then(Vec::pop) else {
+            return false;
+        };
+        for undo in step.undo.into_iter().rev() {
+            match undo {
+                Undo::Inst { bank, index, value } => {
+                    self.inst_mem.content[bank as usize][index as usize] = value
+                }
+                Undo::Data { bank, index, value } => {
+                    self.data_mem.content[bank as usize][index as usize] = value
+                }
+                Undo::InstPage(page) => self.inst_mem.pointer = page,
+                Undo::DataPage(page) => self.data_mem.pointer = page,
+            }
+        }
+        self.clear_decode_cache();
+        self.reg_zero = step.pc;
+        self.cycles = step.cycles;
+        true
+    }
+
+    // Every data memory store is written to `sink` as three bytes, bank, index
+    // and value, before it is applied. A store whose record cannot be written
+    // fails instead.
+    pub fn set_write_log(&mut self, sink: impl Write + 'static) {
+        self.write_log = Some(Box::new(sink));
+    }
+
+    // Applies the records of a write log to data memory in order
+    pub fn recover<R: Read>(&mut self, mut log: R) -> io::Result<()> {
+        let mut records = Vec::new();
+        log.read_to_end(&mut records)?;
+        if records.len() % 3 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Truncated write log",
+            ));
+        }
+        for record in records.chunks_exact(3) {
+            let index = record[1] as usize;
+            if index >= 64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Bad write log index",
+                ));
+            }
+            self.data_mem.content[record[0] as usize][index] = record[2];
+        }
+        Ok(())
+    }
+
+    pub(crate) fn log_data_write(&mut self, address: u8, value: u8) -> io::Result<()> {
+        match (&mut self.write_log, address) {
+            (Some(sink), 128..=191) => {
+                sink.write_all(&[self.data_mem.pointer, address - 128, value])
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn journal_step(&mut self) {
+        if let Some(journal) = &mut self.journal {
+            journal.push(JournalStep {
+                pc: self.reg_zero,
+                cycles: self.cycles,
+                undo: Vec::new(),
+            });
+        }
+    }
+
+    pub(crate) fn journal_write(&mut self, address: u8) {
+        if self.journal.is_none() {
+            return;
+        }
+        let undo = match address {
+            1..=126 => Undo::Inst {
+                bank: self.inst_mem.pointer,
+                index: address,
+                value: self.inst_mem[address as usize],
+            },
+            128..=191 => Undo::Data {
+                bank: self.data_mem.pointer,
+                index: address - 128,
+                value: self.data_mem[(address - 128) as usize],
+            },
+            192 => Undo::InstPage(self.inst_mem.pointer),
+            193 => Undo::DataPage(self.data_mem.pointer),
+            // The PC is restored from the step itself
+            _ => return,
+        };
+        if let Some(step) = self.journal.as_mut().and_then(|journal| journal.last_mut()) {
+            step.undo.push(undo);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::assembler;
+
+    fn load(source: &str) -> CPU {
+        let code = assembler::assemble(source).unwrap();
+        let mut inst_mem = [0; 127];
+        inst_mem[..code.len()].copy_from_slice(&code);
+        CPU::new(inst_mem, Vec::new())
+    }
+
+    #[test]
+    fn step_back_reverts_ticks() {
+        let mut cpu = load("NOOP #05 #02\nCOPY #01 #80\nCOPY #02 #C1\nCOPY #02 #7E");
+        cpu.enable_journal();
+        cpu.run(3);
+        assert_eq!(cpu.data_mem.pointer, 2);
+        cpu.tick();
+        assert_eq!(cpu.inst_mem[126], 2);
+
+        assert!(cpu.step_back());
+        assert_eq!(cpu.inst_mem[126], 0);
+        assert_eq!(cpu.reg_zero, 9);
+        while cpu.step_back() {}
+        assert_eq!((cpu.reg_zero, cpu.cycles), (0, 0));
+        assert_eq!((cpu.data_mem.pointer, cpu.data_mem[0]), (0, 0));
+        assert!(!cpu.step_back());
+    }
+
+    // Keeps what was written readable after the CPU took ownership
+    #[derive(Clone, Default)]
+    struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_log_recovers_data_memory() {
+        let mut cpu = load("NOOP #07 #01\nCOPY #01 #80\nCOPY #02 #C1\nCOPY #01 #81\nCOPY #01 #7E");
+        let log = SharedLog::default();
+        cpu.set_write_log(log.clone());
+        cpu.run(5);
+        assert_eq!(*log.0.borrow(), [0, 0, 7, 1, 1, 7]);
+
+        let mut recovered = CPU::default();
+        recovered.recover(&log.0.borrow()[..]).unwrap();
+        assert_eq!(recovered.data_mem_bytes(), cpu.data_mem_bytes());
+    }
+
+    #[test]
+    fn recover_rejects_bad_logs() {
+        let mut cpu = CPU::default();
+        let error = cpu.recover(&[0, 1][..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let error = cpu.recover(&[0, 64, 1][..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn stores_fail_when_the_log_cannot_be_written() {
+        let mut cpu = load("COPY #01 #80");
+        // too small to hold one record
+        cpu.set_write_log(io::Cursor::new([0_u8; 2]));
+        cpu.tick();
+        assert_eq!(cpu.data_mem[0], 0);
+    }
+}
@@ -1,9 +1,37 @@
+use std::{error::Error, fmt};
+
+use assembler::AssembleError;
 use symbols::Symbol;
 
+pub mod aliasing;
+pub mod alu;
+pub mod analysis;
+pub mod assembler;
 pub mod banking;
+pub mod debug;
 pub mod devices;
 pub mod emulation;
+pub mod journal;
+pub mod linker;
+pub mod profiling;
+pub mod program;
+pub mod protection;
+pub mod snapshot;
+pub mod spec;
 pub mod symbols;
+pub mod system;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+// Bumped whenever the meaning of an opcode or option bit changes, see
+// CHANGELOG.md
+pub const ISA_VERSION: u8 = 1;
+
+// Whether `raw` is an opcode this interpreter executes. Every 4 bit value is,
+// so only values with higher bits set are reported missing.
+pub fn has_instruction(raw: u8) -> bool {
+    OpCode::try_from(raw).is_ok()
+}
 
 #[derive(Debug, Clone)]
 pub struct Instruction {
@@ -37,68 +65,85 @@ pub enum OpCode {
 pub struct OpOptions(u8);
 
 impl Instruction {
-    pub fn from_text(string: &str) -> Option<Self> {
+    // `line` is only used for errors
+    pub fn from_text(string: &str, line: usize) -> Result<Self, AssembleError> {
         let mut parts = string.split_whitespace();
 
         use OpCode::*;
-        let code = match parts.next()? {
-            "NOOP" => NoOp,
-            "AND" => And,
-            "OR" => Or,
-            "NOT" => Not,
-            "ADD" => Add,
-            "SUB" => Sub,
-            "MUL" => Mul,
-            "DIV" => Div,
-            "SL" => SL,
-            "SR" => SR,
-            "RL" => RL,
-            "RR" => RR,
-            "COPY" => Copy,
-            "COMPEQ" => CompEq,
-            "COMPGT" => CompGt,
-            "COMPLE" => CompLt,
-            _ => return None,
+        let code = match parts.next() {
+            Some("NOOP") => NoOp,
+            Some("AND") => And,
+            Some("OR") => Or,
+            Some("NOT") => Not,
+            Some("ADD") => Add,
+            Some("SUB") => Sub,
+            Some("MUL") => Mul,
+            Some("DIV") => Div,
+            Some("SL") => SL,
+            Some("SR") => SR,
+            Some("RL") => RL,
+            Some("RR") => RR,
+            Some("COPY") => Copy,
+            Some("COMPEQ") => CompEq,
+            Some("COMPGT") => CompGt,
+            Some("COMPLE") => CompLt,
+            _ => return Err(AssembleError::UnknownInstruction { line }),
         };
 
         let mut args = Vec::new();
         let mut raw_options = 0b0000;
 
         for part in parts {
-            let (prefix, value) = part.split_at(1);
+            let token = || part.to_owned();
+            let prefix_len = part.chars().next().map_or(0, char::len_utf8);
+            let (prefix, value) = part.split_at(prefix_len);
             match prefix {
                 ":" => {
+                    if value.chars().count() > 4 {
+                        return Err(AssembleError::TooManyOptionBits { line });
+                    }
                     for (i, option) in value.chars().enumerate() {
                         let flag = if option == '1' { 1 } else { 0 };
                         raw_options |= flag << (3 - i);
                     }
                 }
-                // Ideally we would not panic here but return `None` / an appropriate error
-                "#" => args.push(Symbol::Resolved(u8::from_str_radix(value, 16).unwrap())),
+                "#" => {
+                    let address =
+                        u8::from_str_radix(value, 16).map_err(|_| AssembleError::BadHex {
+                            token: token(),
+                            line,
+                        })?;
+                    args.push(Symbol::Resolved(address));
+                }
                 "$" => args.push(Symbol::UnResolved(value.to_owned(), 0)),
-                "+" | "-" => match args.last_mut() {
-                    Some(arg) => match arg {
-                        Symbol::Resolved(_) => return None,
-                        Symbol::UnResolved(_, offset) => {
-                            *offset +=
-                                i8::from_str_radix(&(prefix.to_owned() + value), 16).unwrap();
-                        }
-                    },
-                    None => return None,
-                },
-                _ => return None,
+                "+" | "-" => {
+                    // Offsets only apply to the label right before them
+                    let bad_offset = || AssembleError::BadOffset {
+                        token: token(),
+                        line,
+                    };
+                    let Some(Symbol::UnResolved(_, offset)) = args.last_mut() else {
+                        return Err(bad_offset());
+                    };
+                    let delta = i8::from_str_radix(&(prefix.to_owned() + value), 16)
+                        .map_err(|_| bad_offset())?;
+                    *offset = offset.checked_add(delta).ok_or_else(bad_offset)?;
+                }
+                _ => {
+                    return Err(AssembleError::BadPrefix {
+                        token: token(),
+                        line,
+                    })
+                }
             }
         }
 
         let mut args = args.into_iter();
-        let arg1 = args.next()?;
-        let arg2 = args.next()?;
-        if args.next().is_some() {
-            // more than 2 args were provided, we could return a descriptive error here
-            return None;
-        }
+        let (Some(arg1), Some(arg2), None) = (args.next(), args.next(), args.next()) else {
+            return Err(AssembleError::ArgumentCount { line });
+        };
 
-        Some(Self {
+        Ok(Self {
             code,
             options: OpOptions(raw_options),
             arg1,
@@ -115,8 +160,12 @@ impl Instruction {
     }
 
     pub fn decode(raw: [u8; 3]) -> Self {
+        Self::decode_with(raw, &OpCodeTable::default())
+    }
+
+    pub fn decode_with(raw: [u8; 3], table: &OpCodeTable) -> Self {
         Self {
-            code: OpCode::decode(raw[0]),
+            code: table.get(raw[0] & 0b0000_1111),
             options: OpOptions::decode(raw[0]),
             arg1: Symbol::Resolved(raw[1]),
             arg2: Symbol::Resolved(raw[2]),
@@ -124,48 +173,143 @@ impl Instruction {
     }
 }
 
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code.mnemonic())?;
+        if self.options.0 != 0 {
+            write!(f, " :{:04b}", self.options.0)?;
+        }
+        write!(f, " {} {}", self.arg1, self.arg2)
+    }
+}
+
 impl OpCode {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Self::NoOp => "NOOP",
+            Self::And => "AND",
+            Self::Or => "OR",
+            Self::Not => "NOT",
+            Self::Add => "ADD",
+            Self::Sub => "SUB",
+            Self::Mul => "MUL",
+            Self::Div => "DIV",
+            Self::SL => "SL",
+            Self::SR => "SR",
+            Self::RL => "RL",
+            Self::RR => "RR",
+            Self::Copy => "COPY",
+            Self::CompEq => "COMPEQ",
+            Self::CompGt => "COMPGT",
+            Self::CompLt => "COMPLE",
+        }
+    }
+
+    // How many of the two address operands the instruction uses, unused ones
+    // are ignored
+    pub fn operands(self) -> u8 {
+        match self {
+            Self::NoOp => 0,
+            Self::Not | Self::SL | Self::SR | Self::RL | Self::RR => 1,
+            _ => 2,
+        }
+    }
+
     fn encode(self) -> u8 {
         self as u8
     }
+}
 
-    fn decode(raw: u8) -> Self {
-        match 0b0000_1111 & raw {
-            0b0000 => Self::NoOp,
-            0b0001 => Self::And,
-            0b0010 => Self::Or,
-            0b0011 => Self::Not,
-            0b0100 => Self::Add,
-            0b0101 => Self::Sub,
-            0b0110 => Self::Mul,
-            0b0111 => Self::Div,
-            0b1000 => Self::SL,
-            0b1001 => Self::SR,
-            0b1010 => Self::RL,
-            0b1011 => Self::RR,
-            0b1100 => Self::Copy,
-            0b1101 => Self::CompEq,
-            0b1110 => Self::CompGt,
-            0b1111 => Self::CompLt,
-            _ => panic!("Invalid opcode (This can never happen!)"),
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidOpCode(pub u8);
+
+impl TryFrom<u8> for OpCode {
+    type Error = InvalidOpCode;
+
+    fn try_from(raw: u8) -> Result<Self, Self::Error> {
+        match raw {
+            0b0000 => Ok(Self::NoOp),
+            0b0001 => Ok(Self::And),
+            0b0010 => Ok(Self::Or),
+            0b0011 => Ok(Self::Not),
+            0b0100 => Ok(Self::Add),
+            0b0101 => Ok(Self::Sub),
+            0b0110 => Ok(Self::Mul),
+            0b0111 => Ok(Self::Div),
+            0b1000 => Ok(Self::SL),
+            0b1001 => Ok(Self::SR),
+            0b1010 => Ok(Self::RL),
+            0b1011 => Ok(Self::RR),
+            0b1100 => Ok(Self::Copy),
+            0b1101 => Ok(Self::CompEq),
+            0b1110 => Ok(Self::CompGt),
+            0b1111 => Ok(Self::CompLt),
+            _ => Err(InvalidOpCode(raw)),
+        }
+    }
+}
+
+// Maps each of the 16 raw opcode values to the instruction it decodes to
+#[derive(Debug, Clone, Copy)]
+pub struct OpCodeTable([OpCode; 16]);
+
+#[derive(Debug, Clone, Copy)]
+pub struct MissingOpCode(pub OpCode);
+
+impl fmt::Display for MissingOpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "opcode table has no entry for {}", self.0.mnemonic())
+    }
+}
+
+impl Error for MissingOpCode {}
+
+impl OpCodeTable {
+    // Every instruction has to be reachable, so the table must be a permutation
+    pub fn new(table: [OpCode; 16]) -> Result<Self, MissingOpCode> {
+        for raw in 0..16 {
+            let code = OpCode::try_from(raw).unwrap();
+            if !table.iter().any(|&entry| entry as u8 == raw) {
+                return Err(MissingOpCode(code));
+            }
         }
+        Ok(Self(table))
+    }
+
+    pub fn get(&self, raw: u8) -> OpCode {
+        self.0[raw as usize]
+    }
+
+    // The raw value `code` is encoded as under this table
+    pub fn raw(&self, code: OpCode) -> u8 {
+        let index = self.0.iter().position(|&entry| entry as u8 == code as u8);
+        index.unwrap() as u8
     }
 }
 
+impl Default for OpCodeTable {
+    fn default() -> Self {
+        Self(std::array::from_fn(|raw| {
+            OpCode::try_from(raw as u8).unwrap()
+        }))
+    }
+}
+
+// Holds the upper nibble of the first instruction byte, shifted down
 impl OpOptions {
     pub fn arg1_signed(&self) -> bool {
-        self.0 & 0b0010_0000 == 0b0010_0000
+        self.0 & 0b0010 == 0b0010
     }
 
     pub fn arg2_signed(&self) -> bool {
-        self.0 & 0b0001_0000 == 0b0001_0000
+        self.0 & 0b0001 == 0b0001
     }
 
     pub fn halt_on_error(&self) -> bool {
-        self.0 & 0b1000_0000 == 0b1000_0000
+        self.0 & 0b1000 == 0b1000
     }
 
-    fn encode(self) -> u8 {
+    pub(crate) fn encode(self) -> u8 {
         self.0
     }
 
@@ -173,3 +317,126 @@ impl OpOptions {
         Self(raw >> 4)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcode_try_from_raw_nibble() {
+        for raw in 0..16 {
+            assert_eq!(OpCode::try_from(raw).unwrap() as u8, raw);
+        }
+        assert!(matches!(OpCode::try_from(0x0C), Ok(OpCode::Copy)));
+        assert_eq!(OpCode::try_from(16).unwrap_err(), InvalidOpCode(16));
+    }
+
+    #[test]
+    fn option_bits_select_options() {
+        let options = |text: &str| Instruction::from_text(text, 1).unwrap().options;
+        assert!(options("ADD :1000 #80 #81").halt_on_error());
+        assert!(!options("ADD :1000 #80 #81").arg1_signed());
+        assert!(options("ADD :0010 #80 #81").arg1_signed());
+        assert!(options("ADD :0001 #80 #81").arg2_signed());
+        assert!(!options("ADD :0001 #80 #81").arg1_signed());
+
+        let decoded = Instruction::decode([0b1000_0100, 0x80, 0x81]);
+        assert!(decoded.options.halt_on_error());
+        assert_eq!(decoded.encode(), [0b1000_0100, 0x80, 0x81]);
+    }
+
+    #[test]
+    fn signed_option_changes_comparisons() {
+        // 0xFF is -1 signed, so it is only greater than 1 when unsigned
+        let source = |options: &str| {
+            format!("NOOP #FF #01\nCOMPGT {options} #01 #02\nCOPY #01 #80\nNOOP #00 #00")
+        };
+        let cpu = assembler::run_source(&source("")).unwrap();
+        assert_eq!(cpu.data_mem[0], 0xFF);
+        let cpu = assembler::run_source(&source(":0010")).unwrap();
+        assert_eq!(cpu.data_mem[0], 0);
+    }
+
+    #[test]
+    fn halt_on_error_option_stops_at_a_fault() {
+        let run = |source: &str| {
+            let code = assembler::assemble(source).unwrap();
+            let mut inst_mem = [0; 127];
+            inst_mem[..code.len()].copy_from_slice(&code);
+            let mut cpu = emulation::CPU::new(inst_mem, Vec::new());
+            // makes the first load fail
+            cpu.data_reads_from_code = false;
+            cpu.run(10);
+            cpu.data_mem[2]
+        };
+        assert_eq!(run("COPY #01 #80\nCOPY #00 #82"), 3);
+        assert_eq!(run("COPY :1000 #01 #80\nCOPY #00 #82"), 0);
+    }
+
+    #[test]
+    fn isa_version_matches_the_changelog() {
+        let changelog = include_str!("../../CHANGELOG.md");
+        assert!(changelog.contains(&format!("## ISA version {ISA_VERSION}")));
+    }
+
+    fn swapped_table() -> OpCodeTable {
+        // NOOP and COPY trade raw values
+        let mut table = OpCodeTable::default().0;
+        table.swap(0x0, 0xC);
+        OpCodeTable::new(table).unwrap()
+    }
+
+    #[test]
+    fn opcode_table_remaps_raw_values() {
+        let table = swapped_table();
+        assert!(matches!(table.get(0x0), OpCode::Copy));
+        assert!(matches!(table.get(0xC), OpCode::NoOp));
+        assert_eq!(table.raw(OpCode::Copy), 0x0);
+        assert!(matches!(
+            Instruction::decode_with([0x00, 0x01, 0x80], &table).code,
+            OpCode::Copy
+        ));
+
+        let mut cpu = emulation::CPU::new([0; 127], Vec::new());
+        cpu.inst_mem[0..3].copy_from_slice(&[0x00, 0x01, 0x80]);
+        cpu.opcode_table = table;
+        cpu.tick();
+        // copies its own first operand
+        assert_eq!(cpu.data_mem[0], 0x01);
+    }
+
+    #[test]
+    fn analysis_and_disassembly_decode_with_the_table() {
+        // a jump to 04 once raw 0 decodes as COPY
+        let program = [0x00, 0x01, 0x00];
+        let table = swapped_table();
+        assert!(analysis::jump_targets(&program, &OpCodeTable::default())
+            .targets
+            .is_empty());
+        assert_eq!(
+            analysis::jump_targets(&program, &table).targets,
+            std::collections::BTreeSet::from([4])
+        );
+        let options = debug::DisasmOptions::default();
+        assert_eq!(
+            debug::disassemble(&program, &options, &table),
+            "00: COPY #01 #00\n"
+        );
+    }
+
+    #[test]
+    fn opcode_table_must_be_a_permutation() {
+        let mut table = OpCodeTable::default().0;
+        table[0xC] = OpCode::NoOp;
+        assert!(matches!(
+            OpCodeTable::new(table),
+            Err(MissingOpCode(OpCode::Copy))
+        ));
+    }
+
+    #[test]
+    fn has_instruction_reports_implemented_opcodes() {
+        assert!(has_instruction(OpCode::Copy as u8));
+        assert!(!has_instruction(0x10));
+    }
+}
@@ -0,0 +1,149 @@
+use std::{collections::BTreeMap, error::Error, fmt};
+
+use crate::debug::DebugInfo;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    DuplicateLabel(String),
+    UndefinedLabel(String),
+    TooLong(usize),
+    // A relocation or import offset lies outside its module
+    BadOffset(u8),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::DuplicateLabel(name) => write!(f, "Label {name} defined by several modules"),
+            LinkError::UndefinedLabel(name) => {
+                write!(f, "Label {name} is not defined by any module")
+            }
+            LinkError::TooLong(length) => {
+                write!(
+                    f,
+                    "Linked program of {length} bytes exceeds instruction memory"
+                )
+            }
+            LinkError::BadOffset(offset) => {
+                write!(f, "Offset {offset} lies outside its module")
+            }
+        }
+    }
+}
+
+impl Error for LinkError {}
+
+// Source lines are dropped since they refer to different files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkedProgram {
+    pub code: Vec<u8>,
+    pub debug_info: DebugInfo,
+}
+
+// Places the modules from assembler::assemble_module one after another,
+// relocates their own label references and fills in their imports
+pub fn link(modules: &[(Vec<u8>, DebugInfo)]) -> Result<LinkedProgram, LinkError> {
+    let length: usize = modules.iter().map(|(code, _)| code.len()).sum();
+    if length > 127 {
+        return Err(LinkError::TooLong(length));
+    }
+
+    let mut bases = Vec::with_capacity(modules.len());
+    let mut labels = BTreeMap::new();
+    let mut base = 0_u8;
+    for (code, info) in modules {
+        for (name, addr) in &info.labels {
+            if labels
+                .insert(name.clone(), addr.wrapping_add(base))
+                .is_some()
+            {
+                return Err(LinkError::DuplicateLabel(name.clone()));
+            }
+        }
+        bases.push(base);
+        base += code.len() as u8;
+    }
+
+    let mut linked = LinkedProgram {
+        code: Vec::with_capacity(length),
+        debug_info: DebugInfo {
+            labels: labels.clone(),
+            ..DebugInfo::default()
+        },
+    };
+    for ((code, info), base) in modules.iter().zip(bases) {
+        let offsets = info
+            .relocations
+            .iter()
+            .chain(info.imports.iter().map(|import| &import.offset));
+        if let Some(&offset) = offsets
+            .clone()
+            .find(|&&offset| offset as usize >= code.len())
+        {
+            return Err(LinkError::BadOffset(offset));
+        }
+        let start = linked.code.len();
+        linked.code.extend_from_slice(code);
+        let module = &mut linked.code[start..];
+        for &offset in &info.relocations {
+            module[offset as usize] = module[offset as usize].wrapping_add(base);
+        }
+        for import in &info.imports {
+            let Some(&addr) = labels.get(&import.name) else {
+                return Err(LinkError::UndefinedLabel(import.name.clone()));
+            };
+            module[import.offset as usize] = (addr as i16 + import.addend as i16) as u8;
+        }
+        linked
+            .debug_info
+            .relocations
+            .extend(offsets.map(|offset| offset + base));
+    }
+    Ok(linked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, debug::Import, emulation::CPU};
+
+    // The subroutine fixture split in two, with the subroutine linked first
+    // so every absolute operand of the main module has to move
+    const DOUBLE: &str = "NOOP $main #00\nCOPY #01 #00\n$double\nADD #80 #80\nCOPY #81 #00";
+    const MAIN: &str = "$main\nNOOP $double $return\nNOOP #07 #00\nCOPY #04 #80\nCOPY #02 #81\nCOPY #01 #00\n$return\nDIV :1000 #80 #05";
+
+    #[test]
+    fn linked_modules_run_like_one_program() {
+        let modules = [
+            assembler::assemble_module(DOUBLE).unwrap(),
+            assembler::assemble_module(MAIN).unwrap(),
+        ];
+        let linked = link(&modules).unwrap();
+        assert_eq!(linked.debug_info.labels["main"], 9);
+
+        let mut cpu = CPU::with_image(&linked.code, &[], Vec::new()).unwrap();
+        cpu.run(100);
+        assert_eq!(cpu.data_mem[0], 14);
+    }
+
+    #[test]
+    fn link_errors() {
+        let main = assembler::assemble_module(MAIN).unwrap();
+        assert_eq!(
+            link(std::slice::from_ref(&main)),
+            Err(LinkError::UndefinedLabel("double".to_owned()))
+        );
+        assert_eq!(
+            link(&[main.clone(), main.clone()]),
+            Err(LinkError::DuplicateLabel("main".to_owned()))
+        );
+
+        let (code, mut info) = main;
+        info.imports.push(Import {
+            offset: 200,
+            name: "main".to_owned(),
+            addend: 0,
+        });
+        assert_eq!(link(&[(code, info)]), Err(LinkError::BadOffset(200)));
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use crate::OpCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Region {
+    ProgramCounter,
+    Instructions,
+    Data,
+    Paging,
+    Device(u8),
+}
+
+impl Region {
+    pub fn of(addr: u8) -> Self {
+        match addr {
+            0 => Region::ProgramCounter,
+            1..=127 => Region::Instructions,
+            128..=191 => Region::Data,
+            192 | 193 => Region::Paging,
+            _ => Region::Device(addr),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AccessCount {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub region: Region,
+    pub addr: u8,
+    pub is_write: bool,
+    pub value: u8,
+}
+
+impl Access {
+    pub fn new(addr: u8, is_write: bool, value: u8) -> Self {
+        Self {
+            region: Region::of(addr),
+            addr,
+            is_write,
+            value,
+        }
+    }
+}
+
+// Instruction start addresses of one bank that were or were not fetched
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CoverageReport {
+    pub executed: BTreeSet<u8>,
+    pub uncovered: BTreeSet<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEntry {
+    pub cycle: u64,
+    pub pc: u8,
+    pub opcode: OpCode,
+}
+
+pub fn trace_csv(trace: &[TraceEntry]) -> String {
+    let mut csv = String::from("cycle,pc,opcode\n");
+    for entry in trace {
+        writeln!(
+            csv,
+            "{},{},{}",
+            entry.cycle,
+            entry.pc,
+            entry.opcode.mnemonic()
+        )
+        .unwrap();
+    }
+    csv
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ProfileReport {
+    pub fetches: u64,
+    pub opcodes: BTreeMap<u8, u64>,
+    pub regions: BTreeMap<Region, AccessCount>,
+}
+
+impl ProfileReport {
+    pub fn get(&self, region: Region) -> AccessCount {
+        self.regions.get(&region).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn record_fetch(&mut self, code: OpCode) {
+        self.fetches += 1;
+        *self.opcodes.entry(code as u8).or_default() += 1;
+    }
+
+    pub(crate) fn record_load(&mut self, region: Region) {
+        self.regions.entry(region).or_default().reads += 1;
+    }
+
+    pub(crate) fn record_push(&mut self, region: Region) {
+        self.regions.entry(region).or_default().writes += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assembler,
+        devices::{Device, RomDevice},
+        emulation::CPU,
+    };
+
+    fn load_code(code: &[u8], devices: Vec<Box<dyn Device>>) -> CPU {
+        let mut inst_mem = [0; 127];
+        inst_mem[..code.len()].copy_from_slice(code);
+        CPU::new(inst_mem, devices)
+    }
+
+    fn load(source: &str) -> CPU {
+        let code = assembler::assemble(source).unwrap();
+        let mut cpu = load_code(&code, Vec::new());
+        cpu.program_len = Some(code.len());
+        cpu
+    }
+
+    #[test]
+    fn counts_accesses_per_region() {
+        // COPY #80 #81, COPY #01 #C1
+        let mut cpu = load_code(&[0x0C, 0x80, 0x81, 0x0C, 0x01, 0xC1], Vec::new());
+        cpu.enable_profiling();
+        cpu.tick();
+        cpu.tick();
+
+        let profile = cpu.profile();
+        assert_eq!(profile.fetches, 2);
+        assert_eq!(
+            profile.get(Region::Data),
+            AccessCount {
+                reads: 1,
+                writes: 1
+            }
+        );
+        assert_eq!(
+            profile.get(Region::Instructions),
+            AccessCount {
+                reads: 1,
+                writes: 0
+            }
+        );
+        assert_eq!(
+            profile.get(Region::Paging),
+            AccessCount {
+                reads: 0,
+                writes: 1
+            }
+        );
+        assert_eq!(profile.get(Region::Device(200)), AccessCount::default());
+    }
+
+    #[test]
+    fn device_accesses_count_per_device() {
+        let rom = RomDevice::new(200, vec![1, 2]).unwrap();
+        // COPY #C8 #80, COPY #C9 #81, COPY #80 #C9, COPY #D0 #80
+        let code = [
+            0x0C, 0xC8, 0x80, 0x0C, 0xC9, 0x81, 0x0C, 0x80, 0xC9, 0x0C, 0xD0, 0x80,
+        ];
+        let mut cpu = load_code(&code, vec![Box::new(rom)]);
+        cpu.enable_profiling();
+        for _ in 0..4 {
+            cpu.tick();
+        }
+
+        let profile = cpu.profile();
+        assert_eq!(
+            profile.get(Region::Device(200)),
+            AccessCount {
+                reads: 2,
+                writes: 1
+            }
+        );
+        assert_eq!(profile.get(Region::Device(201)), AccessCount::default());
+        assert_eq!(profile.get(Region::Device(208)).reads, 1);
+    }
+
+    #[test]
+    fn profiling_is_off_by_default() {
+        // COPY #80 #81
+        let mut cpu = load_code(&[0x0C, 0x80, 0x81], Vec::new());
+        cpu.tick();
+        assert_eq!(cpu.profile().fetches, 0);
+
+        cpu.enable_profiling();
+        cpu.tick();
+        cpu.disable_profiling();
+        assert_eq!(cpu.profile().fetches, 0);
+    }
+
+    #[test]
+    fn region_of_address() {
+        assert_eq!(Region::of(0), Region::ProgramCounter);
+        assert_eq!(Region::of(127), Region::Instructions);
+        assert_eq!(Region::of(191), Region::Data);
+        assert_eq!(Region::of(193), Region::Paging);
+        assert_eq!(Region::of(194), Region::Device(194));
+    }
+
+    #[test]
+    fn trace_exports_as_csv() {
+        // COPY #01 #80, ADD #80 #80
+        let mut cpu = load_code(&[0x0C, 0x01, 0x80, 0x04, 0x80, 0x80], Vec::new());
+        cpu.tick();
+        cpu.enable_trace();
+        cpu.tick();
+        cpu.tick();
+        assert_eq!(cpu.trace_csv(), "cycle,pc,opcode\n1,3,ADD\n2,6,NOOP\n");
+    }
+
+    #[test]
+    fn coverage_reports_executed_and_skipped_instructions() {
+        // The PC is 0 and #02 holds 2, so the copy is skipped
+        let mut cpu = load("COMPEQ #00 #02\nCOPY #80 #80\nNOOP #00 #00");
+        assert_eq!(cpu.coverage(0), CoverageReport::default());
+        cpu.enable_coverage();
+        cpu.strict = true;
+        cpu.run(100);
+        let report = cpu.coverage(0);
+        assert_eq!(report.executed, BTreeSet::from([0, 6]));
+        assert_eq!(report.uncovered, BTreeSet::from([3]));
+    }
+
+    #[test]
+    fn trace_filter_drops_rejected_instructions() {
+        let mut cpu = load("COPY #01 #80\nADD #80 #80\nCOPY #80 #81");
+        cpu.enable_trace();
+        cpu.set_trace_filter(|inst| matches!(inst.code, crate::OpCode::Copy));
+        cpu.run(3);
+        assert_eq!(cpu.trace_csv(), "cycle,pc,opcode\n0,0,COPY\n2,6,COPY\n");
+        // filtered instructions still execute
+        assert_eq!(cpu.data_mem[1], 2);
+    }
+
+    #[test]
+    fn opcode_histogram_counts_executed_instructions() {
+        let mut cpu = load(include_str!("../fixtures/sum_loop.ysm"));
+        assert!(cpu.opcode_histogram().is_empty());
+        cpu.enable_profiling();
+        cpu.run(100);
+        let histogram = cpu.opcode_histogram();
+        // five passes through the loop, COPY is the setup and four jumps back
+        assert_eq!(histogram[&(OpCode::Add as u8)], 5);
+        assert_eq!(histogram[&(OpCode::CompGt as u8)], 5);
+        assert_eq!(histogram[&(OpCode::Copy as u8)], 5);
+        assert_eq!(histogram.values().sum::<u64>(), cpu.profile().fetches);
+    }
+}
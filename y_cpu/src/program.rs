@@ -0,0 +1,167 @@
+use std::{
+    error::Error,
+    fmt, fs,
+    io::{self, Read},
+    path::Path,
+};
+
+pub const MAGIC: [u8; 4] = *b"YCPU";
+pub const VERSION: u8 = 1;
+
+// The largest code and data sections, shared by the reader and the writer
+pub const CODE_CAPACITY: usize = 127;
+pub const DATA_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadProgramHeader {
+    Magic([u8; 4]),
+    Version(u8),
+    Length(usize),
+    DataLength(usize),
+}
+
+impl fmt::Display for BadProgramHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BadProgramHeader::Magic(magic) => write!(f, "Wrong magic bytes {magic:02X?}"),
+            BadProgramHeader::Version(version) => write!(f, "Unsupported version {version}"),
+            BadProgramHeader::Length(length) => {
+                write!(f, "Program length {length} exceeds instruction memory")
+            }
+            BadProgramHeader::DataLength(length) => {
+                write!(
+                    f,
+                    "Data section of {length} bytes does not match its length byte"
+                )
+            }
+        }
+    }
+}
+
+impl Error for BadProgramHeader {}
+
+fn invalid(error: BadProgramHeader) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+// Header layout: 4 magic bytes, format version, program length
+pub fn with_header(program: &[u8]) -> io::Result<Vec<u8>> {
+    if program.len() > CODE_CAPACITY {
+        return Err(invalid(BadProgramHeader::Length(program.len())));
+    }
+    let mut output = Vec::with_capacity(MAGIC.len() + 2 + program.len());
+    output.extend_from_slice(&MAGIC);
+    output.push(VERSION);
+    output.push(program.len() as u8);
+    output.extend_from_slice(program);
+    Ok(output)
+}
+
+pub fn read_header<R: Read>(reader: &mut R, capacity: usize) -> io::Result<usize> {
+    let mut header = [0; 6];
+    reader.read_exact(&mut header)?;
+    let magic = [header[0], header[1], header[2], header[3]];
+    if magic != MAGIC {
+        return Err(invalid(BadProgramHeader::Magic(magic)));
+    }
+    if header[4] != VERSION {
+        return Err(invalid(BadProgramHeader::Version(header[4])));
+    }
+    if header[5] as usize > capacity {
+        return Err(invalid(BadProgramHeader::Length(header[5] as usize)));
+    }
+    Ok(header[5] as usize)
+}
+
+// Program files are a header and code, optionally followed by a data
+// section: one length byte and the initial contents of data bank 0
+pub fn write_program_file<P: AsRef<Path>>(
+    path: P,
+    code: &[u8],
+    data_init: &[u8],
+) -> io::Result<()> {
+    let mut output = with_header(code)?;
+    if data_init.len() > DATA_CAPACITY {
+        return Err(invalid(BadProgramHeader::DataLength(data_init.len())));
+    }
+    if !data_init.is_empty() {
+        output.push(data_init.len() as u8);
+        output.extend_from_slice(data_init);
+    }
+    fs::write(path, output)
+}
+
+pub fn read_program_file<P: AsRef<Path>>(path: P) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    let length = read_header(&mut reader, CODE_CAPACITY)?;
+    let mut code = vec![0; length];
+    reader.read_exact(&mut code)?;
+
+    let mut data_init = Vec::new();
+    reader.read_to_end(&mut data_init)?;
+    if let Some((&length, data)) = data_init.split_first() {
+        if length as usize != data.len() || data.len() > DATA_CAPACITY {
+            return Err(invalid(BadProgramHeader::DataLength(data.len())));
+        }
+        data_init.remove(0);
+    }
+    Ok((code, data_init))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_error(bytes: &[u8], capacity: usize) -> BadProgramHeader {
+        let error = read_header(&mut &bytes[..], capacity).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        *error.into_inner().unwrap().downcast().unwrap()
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let image = with_header(&[1, 2, 3]).unwrap();
+        assert_eq!(&image[..6], b"YCPU\x01\x03");
+        let mut reader = &image[..];
+        assert_eq!(read_header(&mut reader, 127).unwrap(), 3);
+        assert_eq!(reader, [1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert_eq!(
+            header_error(b"YCPX\x01\x00", 127),
+            BadProgramHeader::Magic(*b"YCPX")
+        );
+        assert_eq!(
+            header_error(b"YCPU\x02\x00", 127),
+            BadProgramHeader::Version(2)
+        );
+        assert_eq!(
+            header_error(b"YCPU\x01\x80", 127),
+            BadProgramHeader::Length(128)
+        );
+    }
+
+    #[test]
+    fn program_file_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("y_cpu_round_trip_{}.bin", std::process::id()));
+        write_program_file(&path, &[0x0C; 127], &[7; 64]).unwrap();
+        let (code, data_init) = read_program_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(code, [0x0C; 127]);
+        assert_eq!(data_init, [7; 64]);
+    }
+
+    #[test]
+    fn writer_rejects_what_the_reader_would() {
+        let path = std::env::temp_dir().join(format!("y_cpu_oversized_{}.bin", std::process::id()));
+        let error = write_program_file(&path, &[0; 128], &[]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = write_program_file(&path, &[], &[0; 65]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(!path.exists());
+        assert!(with_header(&[0; 300]).is_err());
+    }
+}
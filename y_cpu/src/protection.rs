@@ -0,0 +1,121 @@
+use crate::{emulation::CPU, profiling::Region};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const ALL: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: true,
+    };
+    pub const READ_ONLY: Permissions = Permissions {
+        read: true,
+        write: false,
+        execute: false,
+    };
+    pub const READ_WRITE: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: false,
+    };
+    pub const READ_EXECUTE: Permissions = Permissions {
+        read: true,
+        write: false,
+        execute: true,
+    };
+
+    fn allows(self, access: AccessKind) -> bool {
+        match access {
+            AccessKind::Read => self.read,
+            AccessKind::Write => self.write,
+            AccessKind::Execute => self.execute,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtectionFault {
+    pub addr: u8,
+    pub access: AccessKind,
+}
+
+impl CPU {
+    // Regions without an entry allow everything, so an empty table keeps
+    // self-modifying code and device writes working as before
+    pub fn set_permissions(&mut self, region: Region, permissions: Permissions) {
+        self.protection.insert(region, permissions);
+    }
+
+    // Records a fault and returns false if `access` to `addr` is not allowed.
+    // Fetches are checked against the instruction region rather than the PC.
+    pub(crate) fn check_access(&mut self, addr: u8, access: AccessKind) -> bool {
+        let region = match access {
+            AccessKind::Execute => Region::Instructions,
+            _ => Region::of(addr),
+        };
+        let permissions = self.protection.get(&region).copied();
+        if permissions.unwrap_or(Permissions::ALL).allows(access) {
+            return true;
+        }
+        self.protection_fault = Some(ProtectionFault { addr, access });
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, emulation::Halted};
+
+    fn load(source: &str) -> CPU {
+        let code = assembler::assemble(source).unwrap();
+        CPU::with_image(&code, &[], Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn read_only_data_rejects_stores() {
+        let mut cpu = load("COPY #01 #80\nCOPY #80 #81");
+        cpu.set_permissions(Region::Data, Permissions::READ_ONLY);
+        cpu.tick();
+        assert_eq!(cpu.data_mem[0], 0);
+        assert_eq!(
+            cpu.protection_fault,
+            Some(ProtectionFault {
+                addr: 0x80,
+                access: AccessKind::Write
+            })
+        );
+    }
+
+    #[test]
+    fn non_executable_instructions_halt() {
+        let mut cpu = load("COPY #01 #80");
+        cpu.set_permissions(Region::Instructions, Permissions::READ_WRITE);
+        assert_eq!(cpu.tick(), Halted::Halted);
+        assert_eq!(cpu.data_mem[0], 0);
+        assert_eq!(cpu.protection_fault.unwrap().access, AccessKind::Execute);
+    }
+
+    #[test]
+    fn read_execute_code_rejects_stores_while_data_stays_writable() {
+        let mut cpu = load("COPY #01 #80\nCOPY #80 #04");
+        cpu.set_permissions(Region::Instructions, Permissions::READ_EXECUTE);
+        cpu.tick();
+        assert_eq!(cpu.data_mem[0], 1);
+        cpu.tick();
+        assert_eq!(cpu.inst_mem[4], 0x80);
+        assert_eq!(cpu.protection_fault.unwrap().access, AccessKind::Write);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::{
+    emulation::{Halted, Reg, CPU},
+    profiling::{Region, TraceEntry},
+};
+
+// Registers and every memory bank, devices are not captured
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuState {
+    pub reg_zero: u8,
+    pub inst_page: u8,
+    pub data_page: u8,
+    pub cycles: u64,
+    pub inst_mem: Box<[[u8; 127]; 256]>,
+    pub data_mem: Box<[[u8; 64]; 256]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegChange {
+    pub reg: Reg,
+    pub old: u8,
+    pub new: u8,
+}
+
+// `index` is relative to the start of the region's bank
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemChange {
+    pub region: Region,
+    pub bank: u8,
+    pub index: u8,
+    pub old: u8,
+    pub new: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateDiff {
+    pub registers: Vec<RegChange>,
+    pub cycles: Option<(u64, u64)>,
+    pub memory: Vec<MemChange>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.cycles.is_none() && self.memory.is_empty()
+    }
+}
+
+impl CpuState {
+    pub fn reg(&self, reg: Reg) -> u8 {
+        match reg {
+            Reg::Zero => self.reg_zero,
+            Reg::InstPage => self.inst_page,
+            Reg::DataPage => self.data_page,
+        }
+    }
+
+    // Lists what changed going from `self` to `other`
+    pub fn diff(&self, other: &CpuState) -> StateDiff {
+        let registers = [Reg::Zero, Reg::InstPage, Reg::DataPage]
+            .into_iter()
+            .filter(|&reg| self.reg(reg) != other.reg(reg))
+            .map(|reg| RegChange {
+                reg,
+                old: self.reg(reg),
+                new: other.reg(reg),
+            })
+            .collect();
+        let cycles = (self.cycles != other.cycles).then_some((self.cycles, other.cycles));
+
+        let mut memory = Vec::new();
+        let banks = [
+            (
+                Region::Instructions,
+                self.inst_mem.as_flattened(),
+                other.inst_mem.as_flattened(),
+                127,
+            ),
+            (
+                Region::Data,
+                self.data_mem.as_flattened(),
+                other.data_mem.as_flattened(),
+                64,
+            ),
+        ];
+        for (region, old, new, bank_size) in banks {
+            for (i, (&old, &new)) in old.iter().zip(new).enumerate() {
+                if old != new {
+                    memory.push(MemChange {
+                        region,
+                        bank: (i / bank_size) as u8,
+                        index: (i % bank_size) as u8,
+                        old,
+                        new,
+                    });
+                }
+            }
+        }
+
+        StateDiff {
+            registers,
+            cycles,
+            memory,
+        }
+    }
+}
+
+impl CPU {
+    pub fn snapshot(&self) -> CpuState {
+        CpuState {
+            reg_zero: self.reg_zero,
+            inst_page: self.inst_mem.pointer,
+            data_page: self.data_mem.pointer,
+            cycles: self.cycles,
+            inst_mem: Box::new(self.inst_mem.content),
+            data_mem: Box::new(self.data_mem.content),
+        }
+    }
+
+    pub fn restore(&mut self, state: &CpuState) {
+        self.reg_zero = state.reg_zero;
+        self.inst_mem.pointer = state.inst_page;
+        self.data_mem.pointer = state.data_page;
+        self.cycles = state.cycles;
+        self.inst_mem.content = *state.inst_mem;
+        self.data_mem.content = *state.data_mem;
+        self.clear_decode_cache();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayError {
+    // The PC or the fetched opcode differed from the recorded step
+    Diverged { step: usize, pc: u8, expected: u8 },
+    // Execution halted while recorded steps were left
+    Halted { step: usize },
+}
+
+// Re-executes an unfiltered trace on a default CPU restored to `initial`,
+// returning the final state if every step fetched what was recorded
+pub fn replay(initial: &CpuState, trace: &[TraceEntry]) -> Result<CpuState, ReplayError> {
+    let mut cpu = CPU::default();
+    cpu.restore(initial);
+    for (step, entry) in trace.iter().enumerate() {
+        let opcode = cpu.fetch().map(|inst| inst.code as u8);
+        if cpu.reg_zero != entry.pc || opcode != Some(entry.opcode as u8) {
+            return Err(ReplayError::Diverged {
+                step,
+                pc: cpu.reg_zero,
+                expected: entry.pc,
+            });
+        }
+        if cpu.tick() == Halted::Halted && step + 1 < trace.len() {
+            return Err(ReplayError::Halted { step });
+        }
+    }
+    Ok(cpu.snapshot())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler;
+
+    fn load(source: &str) -> CPU {
+        let code = assembler::assemble(source).unwrap();
+        let mut inst_mem = [0; 127];
+        inst_mem[..code.len()].copy_from_slice(&code);
+        CPU::new(inst_mem, Vec::new())
+    }
+
+    #[test]
+    fn diff_lists_changed_registers_and_cells() {
+        let mut cpu = load("NOOP #09 #01\nCOPY #01 #80\nCOPY #02 #C1");
+        let before = cpu.snapshot();
+        assert!(before.diff(&cpu.snapshot()).is_empty());
+        cpu.run(3);
+        let diff = before.diff(&cpu.snapshot());
+        assert_eq!(
+            diff.registers,
+            [
+                RegChange {
+                    reg: Reg::Zero,
+                    old: 0,
+                    new: 9
+                },
+                RegChange {
+                    reg: Reg::DataPage,
+                    old: 0,
+                    new: 1
+                }
+            ]
+        );
+        assert_eq!(diff.cycles, Some((0, 3)));
+        assert_eq!(
+            diff.memory,
+            [MemChange {
+                region: Region::Data,
+                bank: 0,
+                index: 0,
+                old: 0,
+                new: 9
+            }]
+        );
+    }
+
+    #[test]
+    fn restore_returns_to_a_snapshot() {
+        let mut cpu = load("NOOP #09 #01\nCOPY #01 #80\nCOPY #01 #7E");
+        let before = cpu.snapshot();
+        cpu.run(3);
+        cpu.restore(&before);
+        assert_eq!(cpu.snapshot(), before);
+        assert_eq!((cpu.data_mem[0], cpu.inst_mem[126]), (0, 0));
+    }
+
+    #[test]
+    fn replay_reproduces_a_traced_run() {
+        let mut cpu = load("NOOP #09 #01\nCOPY #01 #80\nADD #80 #80\nCOPY #02 #C1");
+        let initial = cpu.snapshot();
+        cpu.enable_trace();
+        cpu.run(4);
+        let trace = cpu.trace.clone().unwrap();
+        assert_eq!(replay(&initial, &trace), Ok(cpu.snapshot()));
+
+        let mut diverged = trace.clone();
+        diverged[2].pc = 9;
+        assert_eq!(
+            replay(&initial, &diverged),
+            Err(ReplayError::Diverged {
+                step: 2,
+                pc: 6,
+                expected: 9
+            })
+        );
+    }
+
+    #[test]
+    fn replay_reports_an_early_halt() {
+        let mut cpu = load("COPY :1000 #80 #C8");
+        let initial = cpu.snapshot();
+        cpu.enable_trace();
+        cpu.tick();
+        let trace = cpu.trace.clone().unwrap();
+        let doubled = [trace[0], trace[0]];
+        assert_eq!(
+            replay(&initial, &doubled),
+            Err(ReplayError::Halted { step: 0 })
+        );
+    }
+}
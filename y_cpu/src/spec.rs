@@ -0,0 +1,105 @@
+use crate::OpCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Alu,
+    Memory,
+    Control,
+    System,
+}
+
+impl Category {
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Alu => "alu",
+            Category::Memory => "memory",
+            Category::Control => "control",
+            Category::System => "system",
+        }
+    }
+}
+
+// Every instruction is 3 bytes and encodes two 1-byte address operands,
+// `operands` counts how many of them the instruction uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionSpec {
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub operands: u8,
+    pub operand_width: u8,
+    pub category: Category,
+}
+
+pub fn instruction_set_spec() -> Vec<InstructionSpec> {
+    use OpCode::*;
+    (0..16)
+        .map(|raw| {
+            let code = OpCode::try_from(raw).unwrap();
+            let category = match code {
+                NoOp => Category::System,
+                Not | SL | SR | RL | RR => Category::Alu,
+                And | Or | Add | Sub | Mul | Div => Category::Alu,
+                Copy => Category::Memory,
+                CompEq | CompGt | CompLt => Category::Control,
+            };
+            InstructionSpec {
+                opcode: raw,
+                mnemonic: code.mnemonic(),
+                operands: code.operands(),
+                operand_width: 1,
+                category,
+            }
+        })
+        .collect()
+}
+
+// The spec as a JSON array of objects, one per opcode
+pub fn instruction_set_json() -> String {
+    let entries: Vec<String> = instruction_set_spec()
+        .iter()
+        .map(|spec| {
+            format!(
+                "{{\"opcode\":{},\"mnemonic\":\"{}\",\"operands\":{},\"operand_width\":{},\"category\":\"{}\"}}",
+                spec.opcode,
+                spec.mnemonic,
+                spec.operands,
+                spec.operand_width,
+                spec.category.name()
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Instruction;
+
+    #[test]
+    fn spec_covers_every_opcode() {
+        let spec = instruction_set_spec();
+        assert_eq!(spec.len(), 16);
+        for (raw, entry) in spec.iter().enumerate() {
+            assert_eq!(entry.opcode, raw as u8);
+            assert_eq!(entry.operand_width, 1);
+            // every mnemonic assembles back to its opcode
+            let inst = Instruction::from_text(&format!("{} #00 #00", entry.mnemonic), 1).unwrap();
+            assert_eq!(inst.encode()[0], entry.opcode);
+        }
+        assert_eq!(spec[0x3].operands, 1);
+        assert_eq!(spec[0xD].category, Category::Control);
+    }
+
+    #[test]
+    fn spec_as_json() {
+        let json = instruction_set_json();
+        assert!(json.starts_with(
+            "[{\"opcode\":0,\"mnemonic\":\"NOOP\",\"operands\":0,\"operand_width\":1,\"category\":\"system\"},"
+        ));
+        assert!(json.ends_with(
+            "\"mnemonic\":\"COMPLE\",\"operands\":2,\"operand_width\":1,\"category\":\"control\"}]"
+        ));
+        assert_eq!(json.matches("\"opcode\"").count(), 16);
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt};
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Symbol {
@@ -29,4 +29,37 @@ impl Symbol {
     }
 }
 
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Symbol::Resolved(addr) => write!(f, "#{addr:02X}"),
+            Symbol::UnResolved(name, 0) => write!(f, "${name}"),
+            Symbol::UnResolved(name, offset) if *offset < 0 => {
+                write!(f, "${name} -{:02X}", offset.unsigned_abs())
+            }
+            Symbol::UnResolved(name, offset) => write!(f, "${name} +{offset:02X}"),
+        }
+    }
+}
+
 pub type SymbolTable<'input> = HashMap<&'input str, u8>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_known_labels_with_their_offset() {
+        let table = SymbolTable::from([("end", 0x09)]);
+        let mut symbol = Symbol::UnResolved("end".to_owned(), -3);
+        assert!(symbol.resolve(&table));
+        assert_eq!(symbol, Symbol::Resolved(0x06));
+    }
+
+    #[test]
+    fn undefined_labels_stay_unresolved() {
+        let mut symbol = Symbol::UnResolved("missing".to_owned(), 0);
+        assert!(!symbol.resolve(&SymbolTable::new()));
+        assert_eq!(symbol, Symbol::UnResolved("missing".to_owned(), 0));
+    }
+}
@@ -0,0 +1,187 @@
+use std::mem;
+
+use crate::{
+    devices::{Device, DeviceMap, DeviceOverlap},
+    emulation::{Halted, RunOutcome, CPU},
+    profiling::Access,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreAccess {
+    pub core: usize,
+    pub access: Access,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemOutcome {
+    // Indexed by core
+    pub halted: Vec<bool>,
+    pub rounds: u64,
+}
+
+pub struct System {
+    pub cpus: Vec<CPU>,
+    devices: DeviceMap,
+    pub accesses: Option<Vec<CoreAccess>>,
+}
+
+impl System {
+    pub fn new(cpus: Vec<CPU>, devices: Vec<Box<dyn Device>>) -> Result<System, DeviceOverlap> {
+        Ok(System {
+            cpus,
+            devices: DeviceMap::new(devices)?,
+            accesses: None,
+        })
+    }
+
+    // Collects the accesses of every core into one log, in the order they
+    // happened. Cores with their own access log enabled keep it as well.
+    pub fn enable_access_log(&mut self) {
+        self.accesses.get_or_insert_with(Vec::new);
+    }
+
+    pub fn access_log(&self) -> &[CoreAccess] {
+        self.accesses.as_deref().unwrap_or_default()
+    }
+
+    // Ticks every core once in order
+    pub fn step_all(&mut self) -> Vec<Halted> {
+        (0..self.cpus.len())
+            .map(|core| self.on_core(core, CPU::tick))
+            .collect()
+    }
+
+    // Runs the cores round-robin, each with up to `fuel_per_core` ticks per
+    // round, until every core halted or `rounds` rounds have passed
+    pub fn run_system(&mut self, fuel_per_core: u64, rounds: u64) -> SystemOutcome {
+        let mut halted = vec![false; self.cpus.len()];
+        let mut round = 0;
+        while round < rounds && halted.contains(&false) {
+            for (core, halted) in halted.iter_mut().enumerate() {
+                if !*halted {
+                    let outcome = self.on_core(core, |cpu| cpu.run(fuel_per_core));
+                    *halted = outcome == RunOutcome::Halted;
+                }
+            }
+            round += 1;
+        }
+        SystemOutcome {
+            halted,
+            rounds: round,
+        }
+    }
+
+    // While a core runs it sees the shared devices of the system in place of
+    // its own
+    fn on_core<R>(&mut self, core: usize, f: impl FnOnce(&mut CPU) -> R) -> R {
+        let cpu = &mut self.cpus[core];
+        let saved = self
+            .accesses
+            .is_some()
+            .then(|| cpu.accesses.replace(Vec::new()));
+        mem::swap(&mut cpu.devices, &mut self.devices);
+        let result = f(cpu);
+        mem::swap(&mut cpu.devices, &mut self.devices);
+
+        if let (Some(saved), Some(log)) = (saved, &mut self.accesses) {
+            let accesses = mem::replace(&mut cpu.accesses, saved).unwrap_or_default();
+            log.extend(accesses.iter().map(|&access| CoreAccess { core, access }));
+            if let Some(own) = &mut cpu.accesses {
+                own.extend(accesses);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, devices::DeviceError};
+
+    // A single shared byte
+    struct Latch(u8);
+
+    impl Device for Latch {
+        fn load(&mut self, _addr: u8) -> Result<u8, DeviceError> {
+            Ok(self.0)
+        }
+
+        fn push(&mut self, _addr: u8, data: u8) -> Result<(), DeviceError> {
+            self.0 = data;
+            Ok(())
+        }
+
+        fn address(&self) -> u8 {
+            200
+        }
+    }
+
+    fn load(source: &str) -> CPU {
+        let code = assembler::assemble(source).unwrap();
+        CPU::with_image(&code, &[], Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn cores_take_turns_on_shared_devices() {
+        let writer = load("NOOP #05 #00\nCOPY #01 #C8");
+        let reader = load("NOOP #00 #00\nCOPY #C8 #80");
+        let mut system = System::new(vec![writer, reader], vec![Box::new(Latch(0))]).unwrap();
+
+        assert_eq!(system.step_all(), vec![Halted::Running; 2]);
+        assert_eq!(system.step_all(), vec![Halted::Running; 2]);
+        // The writer ran first in the round, so the reader already sees its store
+        assert_eq!(system.cpus[1].data_mem[0], 5);
+    }
+
+    #[test]
+    fn overlapping_shared_devices_are_rejected() {
+        let devices: Vec<Box<dyn Device>> = vec![Box::new(Latch(0)), Box::new(Latch(1))];
+        let error = System::new(vec![load("NOOP #00 #00")], devices).err();
+        assert_eq!(error, Some(DeviceOverlap { address: 200 }));
+    }
+
+    #[test]
+    fn access_log_tags_accesses_with_their_core() {
+        let writer = load("NOOP #05 #00\nCOPY #01 #C8");
+        let mut reader = load("NOOP #00 #00\nCOPY #C8 #80");
+        reader.enable_access_log();
+        let mut system = System::new(vec![writer, reader], vec![Box::new(Latch(0))]).unwrap();
+        system.enable_access_log();
+        system.step_all();
+        assert!(system.access_log().is_empty());
+        system.step_all();
+
+        let shared: Vec<_> = system
+            .access_log()
+            .iter()
+            .filter(|entry| entry.access.addr == 200)
+            .map(|entry| (entry.core, entry.access.is_write, entry.access.value))
+            .collect();
+        assert_eq!(shared, [(0, true, 5), (1, false, 5)]);
+        assert_eq!(system.cpus[1].access_log().len(), 2);
+        assert!(system.cpus[0].access_log().is_empty());
+    }
+
+    #[test]
+    fn run_system_runs_cores_until_all_halt() {
+        let short = load("COPY #01 #80");
+        let long = load(include_str!("../fixtures/sum_loop.ysm"));
+        let mut system = System::new(vec![short, long], Vec::new()).unwrap();
+        let outcome = system.run_system(4, 100);
+        assert_eq!(outcome.halted, [true, true]);
+        assert!(outcome.rounds > 1);
+        assert_eq!(system.cpus[1].data_mem[0], 15);
+
+        let spinning = load("NOOP #00 #00\nCOPY #02 #00");
+        let mut system = System::new(vec![spinning], Vec::new()).unwrap();
+        assert_eq!(
+            system.run_system(10, 3),
+            SystemOutcome {
+                halted: vec![false],
+                rounds: 3
+            }
+        );
+        assert_eq!(system.cpus[0].cycles, 30);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::emulation::{Halted, RunOutcome, CPU};
+
+// CPU wrapper for browser hosts. Its methods only exchange integers, byte
+// vectors and strings, which #[wasm_bindgen] can export as they are, so a
+// binding crate only has to add the attribute. Devices are not supported yet.
+pub struct WasmCpu {
+    cpu: CPU,
+}
+
+impl WasmCpu {
+    // Takes a program with header, as written by program::with_header
+    pub fn new_from_bytes(bytes: &[u8]) -> Result<WasmCpu, String> {
+        let cpu = CPU::from_reader(bytes, Vec::new()).map_err(|error| error.to_string())?;
+        Ok(WasmCpu { cpu })
+    }
+
+    // Returns false once the CPU halted
+    pub fn step(&mut self) -> bool {
+        self.cpu.tick() == Halted::Running
+    }
+
+    // Runs up to `fuel` ticks and returns whether the CPU halted
+    pub fn run_bounded(&mut self, fuel: u32) -> bool {
+        self.cpu.run(fuel as u64) == RunOutcome::Halted
+    }
+
+    pub fn pc(&self) -> u8 {
+        self.cpu.reg_zero
+    }
+
+    pub fn inst_page(&self) -> u8 {
+        self.cpu.inst_mem.pointer
+    }
+
+    pub fn data_page(&self) -> u8 {
+        self.cpu.data_mem.pointer
+    }
+
+    // Saturates at u32::MAX, JavaScript numbers cannot hold every u64
+    pub fn cycles(&self) -> u32 {
+        self.cpu.cycles.min(u32::MAX as u64) as u32
+    }
+
+    // The current data bank
+    pub fn data_mem(&self) -> Vec<u8> {
+        self.cpu.data_mem[0..64].to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program;
+
+    fn load(code: &[u8]) -> WasmCpu {
+        WasmCpu::new_from_bytes(&program::with_header(code).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn steps_and_runs_a_program() {
+        // NOOP #7E #00, COPY #01 #80, COPY #01 #00
+        let mut cpu = load(&[0x00, 0x7E, 0x00, 0x0C, 0x01, 0x80, 0x0C, 0x01, 0x00]);
+        // the final jump leaves instruction memory, which halts with this set
+        cpu.cpu.trap_pc_overflow = true;
+        assert!(cpu.step());
+        assert_eq!((cpu.pc(), cpu.cycles()), (3, 1));
+        assert!(cpu.run_bounded(100));
+        assert_eq!(cpu.data_mem()[0], 0x7E);
+        assert_eq!((cpu.inst_page(), cpu.data_page()), (0, 0));
+        assert!(!cpu.step());
+    }
+
+    #[test]
+    fn run_bounded_stops_when_fuel_runs_out() {
+        // NOOP #00 #00, COPY #02 #00
+        let mut cpu = load(&[0x00, 0x00, 0x00, 0x0C, 0x02, 0x00]);
+        assert!(!cpu.run_bounded(10));
+        assert_eq!(cpu.cycles(), 10);
+    }
+
+    #[test]
+    fn bad_images_are_reported() {
+        let error = WasmCpu::new_from_bytes(b"nope").err().unwrap();
+        assert!(!error.is_empty());
+    }
+}
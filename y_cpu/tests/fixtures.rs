@@ -0,0 +1,65 @@
+use y_cpu::{
+    assembler,
+    emulation::{Halted, CPU},
+};
+
+const FIXTURES: [&str; 3] = [
+    include_str!("../fixtures/multiply.ysm"),
+    include_str!("../fixtures/sum_loop.ysm"),
+    include_str!("../fixtures/subroutine.ysm"),
+];
+
+// Runs `source` with assembler::run_source and checks the data memory byte
+// at `addr`
+fn assert_program_output(source: &str, addr: u8, expected: u8) {
+    assert!(
+        (128..=191).contains(&addr),
+        "{addr:#04X} is not in data memory"
+    );
+    let cpu = assembler::run_source(source).unwrap_or_else(|error| panic!("{error}"));
+    let found = cpu.data_mem[(addr - 128) as usize];
+    assert_eq!(found, expected, "Unexpected value at {addr:#04X}");
+}
+
+// Runs `source` on two fresh CPUs side by side and checks their state
+// hashes, cycle counts and halting agree after every step
+fn assert_deterministic(source: &str, steps: u64) {
+    let code = assembler::assemble(source).unwrap();
+    let fresh = || CPU::with_image(&code, &[], Vec::new()).unwrap();
+    let (mut a, mut b) = (fresh(), fresh());
+    for step in 0..steps {
+        let (halted_a, halted_b) = (a.tick(), b.tick());
+        assert_eq!(halted_a, halted_b, "Halting diverged at step {step}");
+        assert_eq!(
+            a.state_hash(),
+            b.state_hash(),
+            "State diverged at step {step}"
+        );
+        assert_eq!(a.cycles, b.cycles, "Cycles diverged at step {step}");
+        if halted_a == Halted::Halted {
+            return;
+        }
+    }
+}
+
+#[test]
+fn multiply() {
+    assert_program_output(FIXTURES[0], 0x80, 42);
+}
+
+#[test]
+fn sum_loop() {
+    assert_program_output(FIXTURES[1], 0x80, 15);
+}
+
+#[test]
+fn subroutine() {
+    assert_program_output(FIXTURES[2], 0x80, 14);
+}
+
+#[test]
+fn fixtures_are_deterministic() {
+    for source in FIXTURES {
+        assert_deterministic(source, 1000);
+    }
+}
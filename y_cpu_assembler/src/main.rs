@@ -1,40 +1,27 @@
-use std::{env, fs};
+use std::{env, fs, process};
 
-use y_cpu::{symbols::SymbolTable, Instruction};
+use y_cpu::{assembler, program};
 
 fn main() {
-    let input_path = env::args().nth(1).expect("Missing input file path");
+    let Some(input_path) = env::args().nth(1) else {
+        fail("Missing input file path");
+    };
     let output_path = input_path.replace(".ysm", ".bin");
 
-    let mut instructions = Vec::new();
-    let mut symbols = SymbolTable::new();
-    let mut pos = 0_u8;
+    let ysm = fs::read_to_string(&input_path)
+        .unwrap_or_else(|error| fail(&format!("Cannot read {input_path}: {error}")));
+    let output = assembler::assemble(&ysm).unwrap_or_else(|error| {
+        eprint!("{}", error.render(&ysm));
+        process::exit(1);
+    });
 
-    let ysm = fs::read_to_string(input_path).unwrap();
-    for (line, line_no) in ysm.lines().zip(1..) {
-        if line.starts_with("//") || line.is_empty() {
-            continue;
-        }
-
-        if let Some(name) = line.strip_prefix('$') {
-            symbols.insert(name, pos - 3);
-        } else {
-            let instr = Instruction::from_text(line)
-                .unwrap_or_else(|| panic!("Unknown instruction on line {line_no}"));
-            instructions.push(instr);
-            pos += 3;
-        }
-    }
-
-    for Instruction { arg1, arg2, .. } in &mut instructions {
-        arg1.resolve(&symbols);
-        arg2.resolve(&symbols);
+    let image = program::with_header(&output).unwrap_or_else(|error| fail(&error.to_string()));
+    if let Err(error) = fs::write(&output_path, image) {
+        fail(&format!("Cannot write {output_path}: {error}"));
     }
+}
 
-    let output = instructions
-        .iter()
-        .flat_map(Instruction::encode)
-        .collect::<Vec<_>>();
-
-    fs::write(output_path, output).unwrap();
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    process::exit(1);
 }
@@ -1,28 +1,87 @@
 use std::{
-    fs::File,
+    env,
     io::{self, Read},
+    process,
 };
 
-use y_cpu::emulation::CPU;
+use y_cpu::{
+    assembler::DEFAULT_FUEL,
+    emulation::{Halted, RunOutcome, CPU},
+    program,
+};
 
+// Usage: y_cpu_cli [program.bin] [--fuel N] [--trace] [--step]
 fn main() {
-    let mut file = File::open("example.bin").unwrap();
-    let mut binary = [0_u8; 127];
+    let mut path = String::from("example.bin");
+    let mut fuel = DEFAULT_FUEL;
+    let mut trace = false;
+    let mut step = false;
 
-    file.read_exact(&mut binary).unwrap();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fuel" => {
+                let Some(value) = args.next() else {
+                    fail("Missing value for --fuel");
+                };
+                fuel = value
+                    .parse()
+                    .unwrap_or_else(|_| fail(&format!("Fuel must be a number, not {value}")));
+            }
+            "--trace" => trace = true,
+            "--step" => step = true,
+            _ => path = arg,
+        }
+    }
 
-    let mut cpu = CPU::new(binary, Vec::new());
+    let (code, data_init) = program::read_program_file(&path)
+        .unwrap_or_else(|error| fail(&format!("Cannot read {path}: {error}")));
+    let mut cpu = CPU::with_image(&code, &data_init, Vec::new())
+        .unwrap_or_else(|error| fail(&format!("Cannot load {path}: {error:?}")));
+    if trace {
+        cpu.enable_trace();
+    }
+
+    let outcome = if step {
+        step_through(&mut cpu, fuel)
+    } else {
+        cpu.run(fuel)
+    };
+    if trace {
+        print!("{}", cpu.trace_csv());
+    }
+    print!("{}", cpu.state_report());
+    println!("Outcome: {outcome:?}");
+    if outcome != RunOutcome::Halted {
+        process::exit(1);
+    }
+}
+
+// Usage errors exit with 2, programs that do not halt with 1
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    process::exit(2);
+}
 
-    loop {
-        println!("Next Instruction: {:?}", cpu.fetch());
-        cpu.tick();
+// Ticks once per key press until the CPU halts or `fuel` ticks have passed
+fn step_through(cpu: &mut CPU, fuel: u64) -> RunOutcome {
+    for _ in 0..fuel {
+        match cpu.peek(cpu.reg_zero) {
+            Some(inst) => println!("Next Instruction: {inst}"),
+            None => println!("Next Instruction: none"),
+        }
+        let halted = cpu.tick();
         println!("RegZero: {} Data: {:?}", cpu.reg_zero, &cpu.data_mem[0..3]);
+        if halted == Halted::Halted {
+            return RunOutcome::Halted;
+        }
         pause();
     }
+    RunOutcome::OutOfFuel
 }
 
 fn pause() {
     let mut stdin = io::stdin();
     // Read a single byte and discard
-    let _ = stdin.read(&mut [0u8]).unwrap();
+    let _ = stdin.read(&mut [0u8]);
 }
@@ -0,0 +1,61 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+use y_cpu::{assembler, program};
+
+fn program_file(name: &str, source: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("y_cpu_cli_{}_{name}.bin", std::process::id()));
+    let code = assembler::assemble(source).unwrap();
+    program::write_program_file(&path, &code, &[]).unwrap();
+    path
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_y_cpu_cli"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn runs_a_program_and_prints_the_final_state() {
+    let path = program_file(
+        "sum_loop",
+        include_str!("../../y_cpu/fixtures/sum_loop.ysm"),
+    );
+    let output = run(&[path.to_str().unwrap(), "--trace"]);
+    fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("cycle,pc,opcode\n0,0,NOOP\n"));
+    assert!(stdout.contains("== Registers ==\n"));
+    assert!(stdout.contains("80: 0F 00 00"));
+    assert!(stdout.ends_with("Outcome: Halted\n"));
+}
+
+#[test]
+fn fuel_caps_the_run() {
+    let path = program_file("spin", "NOOP #00 #00\nCOPY #02 #00");
+    let output = run(&[path.to_str().unwrap(), "--fuel", "10"]);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Cycles:      10\n"));
+    assert!(stdout.ends_with("Outcome: OutOfFuel\n"));
+}
+
+#[test]
+fn bad_arguments_are_reported_on_stderr() {
+    for args in [&["missing.bin"][..], &["--fuel"], &["--fuel", "lots"]] {
+        let output = run(args);
+        assert_eq!(output.status.code(), Some(2));
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(!stderr.is_empty() && !stderr.contains("panicked"));
+    }
+}